@@ -0,0 +1,1032 @@
+use crate::blip::BlipBuffer;
+
+pub const CPU_FREQ: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+// The frame sequencer runs at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_FREQ / 512;
+
+// Bits that always read back as 1, indexed from $FF10
+// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Register_Reading
+#[rustfmt::skip]
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1 = 0,
+    Pulse2 = 1,
+    Wave = 2,
+    Noise = 3,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Wave,
+        Channel::Noise,
+    ];
+
+    /// 1-based channel number as used by NR51/NR52 and the pandocs
+    pub fn from_number(number: u8) -> Option<Channel> {
+        return Channel::ALL.get((number as usize).wrapping_sub(1)).copied();
+    }
+}
+
+/// Turns the channel off once the counter runs out.
+/// Pulse and noise channels count 64 steps, the wave channel 256.
+#[derive(Clone)]
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> LengthCounter {
+        return LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        };
+    }
+
+    pub fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns false when the channel has to be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        return true;
+    }
+}
+
+/// NRx2: Volume & envelope
+#[derive(Clone)]
+pub struct Envelope {
+    pub initial_volume: u8,
+    pub increase: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        return Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        };
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.initial_volume = val >> 4;
+        self.increase = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    pub fn read(&self) -> u8 {
+        return (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period;
+    }
+
+    /// The DAC is powered as long as the upper 5 bits of NRx2 are not all 0
+    pub fn dac_enabled(&self) -> bool {
+        return self.read() & 0xF8 != 0;
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        return Envelope::new();
+    }
+}
+
+/// NR10: Channel 1 sweep
+#[derive(Clone)]
+pub struct Sweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+}
+
+impl Sweep {
+    pub fn new() -> Sweep {
+        return Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            enabled: false,
+            shadow: 0,
+            timer: 0,
+        };
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.period = (val >> 4) & 0x07;
+        self.negate = val & 0x08 != 0;
+        self.shift = val & 0x07;
+    }
+
+    pub fn read(&self) -> u8 {
+        return (self.period << 4) | ((self.negate as u8) << 3) | self.shift;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn calculate(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            return self.shadow.wrapping_sub(delta);
+        }
+        return self.shadow + delta;
+    }
+
+    /// Returns false when the overflow check disables the channel
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow = frequency;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        if self.shift != 0 {
+            return self.calculate() <= 2047;
+        }
+        return true;
+    }
+
+    /// Returns the new frequency, or Err(()) when the channel overflowed
+    fn clock(&mut self) -> Result<Option<u16>, ()> {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return Ok(None);
+        }
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return Ok(None);
+        }
+
+        let frequency = self.calculate();
+        if frequency > 2047 {
+            return Err(());
+        }
+        if self.shift == 0 {
+            return Ok(None);
+        }
+        self.shadow = frequency;
+        // The new value is checked again but not written back
+        if self.calculate() > 2047 {
+            return Err(());
+        }
+        return Ok(Some(frequency));
+    }
+}
+
+impl Default for Sweep {
+    fn default() -> Sweep {
+        return Sweep::new();
+    }
+}
+
+/// Channel 1 (with sweep) and channel 2
+#[derive(Clone)]
+pub struct PulseChannel {
+    pub enabled: bool,
+    pub duty: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub sweep: Option<Sweep>,
+    timer: u32,
+    position: usize,
+}
+
+impl PulseChannel {
+    pub fn new(sweep: bool) -> PulseChannel {
+        return PulseChannel {
+            enabled: false,
+            duty: 0,
+            frequency: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+            timer: 0,
+            position: 0,
+        };
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0, |s| s.read()),
+            1 => self.duty << 6,
+            2 => self.envelope.read(),
+            3 => 0,
+            4 => (self.length.enabled as u8) << 6,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.write(val);
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0x3F);
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((val & 0x07) as u16) << 8);
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = (2048 - self.frequency as u32) * 4;
+        if let Some(sweep) = self.sweep.as_mut() {
+            if !sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency as u32) * 4;
+            self.position = (self.position + 1) % 8;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if let Some(sweep) = self.sweep.as_mut() {
+            match sweep.clock() {
+                Ok(Some(frequency)) => self.frequency = frequency,
+                Ok(None) => (),
+                Err(()) => self.enabled = false,
+            }
+        }
+    }
+
+    /// Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        return DUTY_TABLE[self.duty as usize][self.position] * self.envelope.volume;
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        return self.envelope.dac_enabled();
+    }
+}
+
+/// Channel 3, plays 32 4-bit samples from wave RAM
+#[derive(Clone)]
+pub struct WaveChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub volume_code: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub wave_ram: [u8; 16],
+    timer: u32,
+    position: usize,
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        return WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+            timer: 0,
+            position: 0,
+        };
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => (self.dac_enabled as u8) << 7,
+            1 => 0,
+            2 => self.volume_code << 5,
+            3 => 0,
+            4 => (self.length.enabled as u8) << 6,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(val),
+            2 => self.volume_code = (val >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((val & 0x07) as u16) << 8);
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = (2048 - self.frequency as u32) * 2;
+        self.position = 0;
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency as u32) * 2;
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.wave_ram[self.position / 2];
+        // upper nibble is played first
+        let sample = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        return match self.volume_code {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            3 => sample >> 2,
+            _ => unreachable!(),
+        };
+    }
+}
+
+impl Default for WaveChannel {
+    fn default() -> WaveChannel {
+        return WaveChannel::new();
+    }
+}
+
+/// Channel 4, pseudo-random output from a linear feedback shift register
+#[derive(Clone)]
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub clock_shift: u8,
+    pub width_mode: bool,
+    pub divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        return NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        };
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => 0,
+            1 => 0,
+            2 => self.envelope.read(),
+            3 => (self.clock_shift << 4) | ((self.width_mode as u8) << 3) | self.divisor_code,
+            4 => (self.length.enabled as u8) << 6,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => (),
+            1 => self.length.load(val & 0x3F),
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = val >> 4;
+                self.width_mode = val & 0x08 != 0;
+                self.divisor_code = val & 0x07;
+            }
+            4 => {
+                self.length.enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        return NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+    }
+
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            let bit = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        // bit 0 inverted
+        return ((!self.lfsr & 1) as u8) * self.envelope.volume;
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        return self.envelope.dac_enabled();
+    }
+}
+
+impl Default for NoiseChannel {
+    fn default() -> NoiseChannel {
+        return NoiseChannel::new();
+    }
+}
+
+/// apu(audio processing unit)
+/// <https://gbdev.io/pandocs/Audio.html>
+/// <https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware>
+///
+/// Four channels (two pulse, wave, noise) are mixed into a stereo signal
+/// which is resampled to a configurable output rate.
+#[derive(Clone)]
+pub struct Apu {
+    pub power: bool,
+    pub ch1: PulseChannel,
+    pub ch2: PulseChannel,
+    pub ch3: WaveChannel,
+    pub ch4: NoiseChannel,
+    // NR50: Master volume & VIN panning
+    pub nr50: u8,
+    // NR51: Sound panning
+    pub nr51: u8,
+
+    pub sample_rate: u32,
+    pub samples: Vec<(f32, f32)>,
+    // Emulates the output capacitor, removing the DC offset of the DACs
+    pub high_pass: bool,
+    // CGB hardware: power off also clears the length counters, and the
+    // output capacitor discharges faster
+    pub cgb: bool,
+    // Channels left out of the mix
+    pub muted: [bool; 4],
+    // Pre-mix DAC output of each channel, collected while capture is on
+    pub channel_samples: Option<[Vec<f32>; 4]>,
+
+    frame_sequencer_timer: u32,
+    frame_sequencer_step: u8,
+
+    left: BlipBuffer,
+    right: BlipBuffer,
+    left_filter: HighPass,
+    right_filter: HighPass,
+    // one buffer per channel while capturing
+    channel_blips: Vec<BlipBuffer>,
+    left_out: Vec<f32>,
+    right_out: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Apu {
+        return Apu {
+            power: false,
+            ch1: PulseChannel::new(true),
+            ch2: PulseChannel::new(false),
+            ch3: WaveChannel::new(),
+            ch4: NoiseChannel::new(),
+            nr50: 0,
+            nr51: 0,
+
+            sample_rate,
+            samples: Vec::new(),
+            high_pass: true,
+            cgb: false,
+            muted: [false; 4],
+            channel_samples: None,
+
+            frame_sequencer_timer: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+
+            left: BlipBuffer::new(CPU_FREQ, sample_rate),
+            right: BlipBuffer::new(CPU_FREQ, sample_rate),
+            left_filter: HighPass::new(sample_rate, false),
+            right_filter: HighPass::new(sample_rate, false),
+            channel_blips: Vec::new(),
+            left_out: Vec::new(),
+            right_out: Vec::new(),
+        };
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.left = BlipBuffer::new(CPU_FREQ, sample_rate);
+        self.right = BlipBuffer::new(CPU_FREQ, sample_rate);
+        self.left_filter = HighPass::new(sample_rate, self.cgb);
+        self.right_filter = HighPass::new(sample_rate, self.cgb);
+        if self.channel_samples.is_some() {
+            self.start_channel_capture();
+        }
+    }
+
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.left_filter = HighPass::new(self.sample_rate, cgb);
+        self.right_filter = HighPass::new(self.sample_rate, cgb);
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    /// Mutes every channel except the given one
+    pub fn solo(&mut self, channel: Channel) {
+        for other in Channel::ALL {
+            self.muted[other as usize] = other != channel;
+        }
+    }
+
+    pub fn unmute_all(&mut self) {
+        self.muted = [false; 4];
+    }
+
+    pub fn start_channel_capture(&mut self) {
+        self.channel_samples = Some(Default::default());
+        self.channel_blips = (0..4)
+            .map(|_| BlipBuffer::new(CPU_FREQ, self.sample_rate))
+            .collect();
+    }
+
+    /// Stops capturing and returns the isolated output of each channel, indexed by `Channel`
+    pub fn stop_channel_capture(&mut self) -> [Vec<f32>; 4] {
+        self.channel_blips.clear();
+        return self.channel_samples.take().unwrap_or_default();
+    }
+
+    /// Returns the samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        return std::mem::take(&mut self.samples);
+    }
+
+    /// Advances by one M-cycle
+    pub fn tick(&mut self) {
+        for _ in 0..4 {
+            self.tick_t_cycle();
+        }
+        self.read_samples();
+    }
+
+    fn tick_t_cycle(&mut self) {
+        if self.power {
+            self.frame_sequencer_timer -= 1;
+            if self.frame_sequencer_timer == 0 {
+                self.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+
+            self.ch1.tick();
+            self.ch2.tick();
+            self.ch3.tick();
+            self.ch4.tick();
+        }
+
+        let channels = self.channel_outputs();
+        for (blip, val) in self.channel_blips.iter_mut().zip(channels) {
+            blip.set_amplitude(val);
+            blip.clock(1);
+        }
+        let (left, right) = self.mix(&channels);
+        self.left.set_amplitude(left);
+        self.left.clock(1);
+        self.right.set_amplitude(right);
+        self.right.clock(1);
+    }
+
+    fn read_samples(&mut self) {
+        self.left.read_samples(&mut self.left_out);
+        self.right.read_samples(&mut self.right_out);
+        for (left, right) in self.left_out.drain(..).zip(self.right_out.drain(..)) {
+            if self.high_pass {
+                let left = self.left_filter.apply(left);
+                let right = self.right_filter.apply(right);
+                self.samples.push((left, right));
+            } else {
+                self.samples.push((left, right));
+            }
+        }
+
+        if let Some(channel_samples) = self.channel_samples.as_mut() {
+            for (blip, buffer) in self
+                .channel_blips
+                .iter_mut()
+                .zip(channel_samples.iter_mut())
+            {
+                blip.read_samples(buffer);
+            }
+        }
+    }
+
+    /// Step   Length Ctr  Vol Env     Sweep
+    /// ---------------------------------------
+    /// 0      Clock       -           -
+    /// 1      -           -           -
+    /// 2      Clock       -           Clock
+    /// 3      -           -           -
+    /// 4      Clock       -           -
+    /// 5      -           -           -
+    /// 6      Clock       -           Clock
+    /// 7      -           Clock       -
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_sequencer_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.ch1.clock_sweep();
+            }
+            7 => {
+                self.ch1.envelope.clock();
+                self.ch2.envelope.clock();
+                self.ch4.envelope.clock();
+            }
+            _ => (),
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.ch1.clock_length();
+        self.ch2.clock_length();
+        self.ch3.clock_length();
+        self.ch4.clock_length();
+    }
+
+    /// Converts the digital output of each channel through its DAC
+    fn channel_outputs(&self) -> [f32; 4] {
+        if !self.power {
+            return [0.0; 4];
+        }
+
+        return [
+            dac(self.ch1.output(), self.ch1.dac_enabled()),
+            dac(self.ch2.output(), self.ch2.dac_enabled()),
+            dac(self.ch3.output(), self.ch3.dac_enabled),
+            dac(self.ch4.output(), self.ch4.dac_enabled()),
+        ];
+    }
+
+    /// Applies NR51 panning and NR50 volume to the unmuted channels
+    fn mix(&self, channels: &[f32; 4]) -> (f32, f32) {
+        if !self.power {
+            return (0.0, 0.0);
+        }
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, val) in channels.iter().enumerate() {
+            if self.muted[i] {
+                continue;
+            }
+            if self.nr51 & (1 << (i + 4)) != 0 {
+                left += val;
+            }
+            if self.nr51 & (1 << i) != 0 {
+                right += val;
+            }
+        }
+
+        let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8.0;
+
+        return (left / 4.0 * left_volume, right / 4.0 * right_volume);
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        let val = match addr {
+            0xFF10..=0xFF14 => self.ch1.read(addr - 0xFF10),
+            0xFF15..=0xFF19 => self.ch2.read(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.ch3.read(addr - 0xFF1A),
+            0xFF1F..=0xFF23 => self.ch4.read(addr - 0xFF1F),
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                ((self.power as u8) << 7)
+                    | ((self.ch4.enabled as u8) << 3)
+                    | ((self.ch3.enabled as u8) << 2)
+                    | ((self.ch2.enabled as u8) << 1)
+                    | (self.ch1.enabled as u8)
+            }
+            0xFF27..=0xFF2F => 0xFF,
+            0xFF30..=0xFF3F => return self.ch3.wave_ram[(addr - 0xFF30) as usize],
+            _ => panic!("Apu::read: invalid address: 0x{:04X}", addr),
+        };
+
+        if addr >= 0xFF27 {
+            return val;
+        }
+        return val | READ_MASKS[(addr - 0xFF10) as usize];
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        // Wave RAM stays accessible while powered off
+        if let 0xFF30..=0xFF3F = addr {
+            self.ch3.wave_ram[(addr - 0xFF30) as usize] = val;
+            return;
+        }
+
+        // NR52: Sound on/off
+        if addr == 0xFF26 {
+            let power = val & 0x80 != 0;
+            if self.power && !power {
+                self.power_off();
+            } else if !self.power && power {
+                self.frame_sequencer_step = 0;
+                self.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+            }
+            self.power = power;
+            return;
+        }
+
+        if !self.power {
+            // On DMG the length counters can still be loaded while powered off
+            if !self.cgb {
+                match addr {
+                    0xFF11 => self.ch1.length.load(val & 0x3F),
+                    0xFF16 => self.ch2.length.load(val & 0x3F),
+                    0xFF1B => self.ch3.length.load(val),
+                    0xFF20 => self.ch4.length.load(val & 0x3F),
+                    _ => (),
+                }
+            }
+            return;
+        }
+
+        match addr {
+            0xFF10..=0xFF14 => self.ch1.write(addr - 0xFF10, val),
+            0xFF15..=0xFF19 => self.ch2.write(addr - 0xFF15, val),
+            0xFF1A..=0xFF1E => self.ch3.write(addr - 0xFF1A, val),
+            0xFF1F..=0xFF23 => self.ch4.write(addr - 0xFF1F, val),
+            0xFF24 => self.nr50 = val,
+            0xFF25 => self.nr51 = val,
+            0xFF27..=0xFF2F => (),
+            _ => panic!("Apu::write: invalid address: 0x{:04X}", addr),
+        }
+    }
+
+    /// Turning the APU off clears every register except wave RAM,
+    /// and on DMG the length counters
+    fn power_off(&mut self) {
+        let wave_ram = self.ch3.wave_ram;
+        let lengths = [
+            self.ch1.length.counter,
+            self.ch2.length.counter,
+            self.ch3.length.counter,
+            self.ch4.length.counter,
+        ];
+        self.ch1 = PulseChannel::new(true);
+        self.ch2 = PulseChannel::new(false);
+        self.ch3 = WaveChannel::new();
+        self.ch3.wave_ram = wave_ram;
+        self.ch4 = NoiseChannel::new();
+        if !self.cgb {
+            self.ch1.length.counter = lengths[0];
+            self.ch2.length.counter = lengths[1];
+            self.ch3.length.counter = lengths[2];
+            self.ch4.length.counter = lengths[3];
+        }
+        self.nr50 = 0;
+        self.nr51 = 0;
+    }
+}
+
+/// High-pass filter formed by the capacitor on the DMG audio output
+/// <https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Obscure_Behavior>
+#[derive(Clone)]
+pub struct HighPass {
+    capacitor: f32,
+    charge_factor: f32,
+}
+
+impl HighPass {
+    pub fn new(sample_rate: u32, cgb: bool) -> HighPass {
+        // per CPU clock
+        let factor = if cgb { 0.998943f32 } else { 0.999958f32 };
+        return HighPass {
+            capacitor: 0.0,
+            charge_factor: factor.powf(CPU_FREQ as f32 / sample_rate as f32),
+        };
+    }
+
+    pub fn apply(&mut self, val: f32) -> f32 {
+        let out = val - self.capacitor;
+        self.capacitor = val - out * self.charge_factor;
+        return out;
+    }
+}
+
+/// Maps a digital value 0-15 to an analog level in -1.0..=1.0
+fn dac(val: u8, enabled: bool) -> f32 {
+    if !enabled {
+        return 0.0;
+    }
+    return 1.0 - (val as f32 / 7.5);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NR52: u16 = 0xFF26;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write(NR52, 0x80);
+        return apu;
+    }
+
+    #[test]
+    fn unreadable_bits_read_as_one() {
+        let mut apu = powered_apu();
+        for addr in 0xFF10..NR52 {
+            apu.write(addr, 0x00);
+            let mask = READ_MASKS[(addr - 0xFF10) as usize];
+            assert_eq!(apu.read(addr), mask, "${:04X}", addr);
+        }
+        // no channel is playing
+        assert_eq!(apu.read(NR52), 0xF0);
+        for addr in 0xFF27..=0xFF2F {
+            assert_eq!(apu.read(addr), 0xFF, "${:04X}", addr);
+        }
+
+        // duty is readable, length is not
+        apu.write(0xFF11, 0x85);
+        assert_eq!(apu.read(0xFF11), 0xBF);
+        apu.write(0xFF24, 0x35);
+        assert_eq!(apu.read(0xFF24), 0x35);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_wave_ram() {
+        let mut apu = powered_apu();
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xFF);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF12, 0xF3);
+        apu.write(0xFF30, 0x12);
+
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF25), 0x00);
+        assert_eq!(apu.read(0xFF11), 0x3F);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        assert_eq!(apu.read(0xFF30), 0x12);
+
+        // registers ignore writes until powered on again
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        apu.write(NR52, 0x80);
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x77);
+    }
+}
@@ -0,0 +1,164 @@
+use std::f64::consts::PI;
+
+// Resolution of the sub-sample position of a delta
+const PHASE_BITS: u32 = 5;
+const PHASES: usize = 1 << PHASE_BITS;
+// Number of output samples touched by one delta
+const WIDTH: usize = 16;
+// Fixed point fraction of the sample position
+const FRAC_BITS: u32 = 32;
+// Fraction of the Nyquist frequency kept by the low-pass kernel
+const CUTOFF: f64 = 0.95;
+
+/// Band-limited synthesis buffer in the style of blip_buf
+/// <http://www.slack.net/~ant/bl-synth/>
+///
+/// Instead of point-sampling the square waves, every change of amplitude is
+/// recorded as a band-limited step at its exact clock time, so the output
+/// contains no frequencies above the Nyquist limit of the output rate.
+#[derive(Clone)]
+pub struct BlipBuffer {
+    // samples per clock in fixed point
+    factor: u64,
+    // current time in output samples (fixed point), relative to buffer[0]
+    pos: u64,
+    // pending deltas, buffer[0] is the next sample to output
+    buffer: Vec<f32>,
+    amplitude: f32,
+    integrator: f32,
+    kernel: Vec<[f32; WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> BlipBuffer {
+        return BlipBuffer {
+            factor: ((sample_rate as u64) << FRAC_BITS) / clock_rate as u64,
+            pos: 0,
+            buffer: vec![0.0; WIDTH],
+            amplitude: 0.0,
+            integrator: 0.0,
+            kernel: make_kernel(),
+        };
+    }
+
+    /// Changes the output level at the current time
+    pub fn set_amplitude(&mut self, amplitude: f32) {
+        let delta = amplitude - self.amplitude;
+        if delta == 0.0 {
+            return;
+        }
+        self.amplitude = amplitude;
+
+        let index = (self.pos >> FRAC_BITS) as usize;
+        let phase = ((self.pos >> (FRAC_BITS - PHASE_BITS)) as usize) & (PHASES - 1);
+        if self.buffer.len() < index + WIDTH {
+            self.buffer.resize(index + WIDTH, 0.0);
+        }
+        for (val, k) in self.buffer[index..index + WIDTH]
+            .iter_mut()
+            .zip(self.kernel[phase].iter())
+        {
+            *val += delta * k;
+        }
+    }
+
+    /// Advances the time by the given number of clocks
+    pub fn clock(&mut self, clocks: u32) {
+        self.pos += self.factor * clocks as u64;
+    }
+
+    /// Moves the finished samples into `out`
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = (self.pos >> FRAC_BITS) as usize;
+        if count == 0 {
+            return;
+        }
+        if self.buffer.len() < count + WIDTH {
+            self.buffer.resize(count + WIDTH, 0.0);
+        }
+
+        for val in self.buffer.drain(..count) {
+            self.integrator += val;
+            out.push(self.integrator);
+        }
+        self.pos -= (count as u64) << FRAC_BITS;
+    }
+}
+
+/// Windowed sinc impulse for each sub-sample phase, delayed by WIDTH / 2 samples
+/// so that a delta never reaches back into samples that were already read.
+/// Each phase sums to 1 so the integrated step settles exactly on the new level.
+fn make_kernel() -> Vec<[f32; WIDTH]> {
+    let mut kernel = vec![[0.0; WIDTH]; PHASES];
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let offset = phase as f64 / PHASES as f64;
+        let mut values = [0.0f64; WIDTH];
+        for (k, val) in values.iter_mut().enumerate() {
+            let x = k as f64 - (WIDTH / 2) as f64 - offset;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+            };
+            // Blackman window over [-WIDTH / 2, WIDTH / 2]
+            let t = (x + (WIDTH / 2) as f64) / WIDTH as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+            *val = sinc * window;
+        }
+
+        let sum: f64 = values.iter().sum();
+        for (tap, val) in taps.iter_mut().zip(values.iter()) {
+            *tap = (val / sum) as f32;
+        }
+    }
+    return kernel;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: u32 = 4_194_304;
+
+    #[test]
+    fn one_second_of_clocks_gives_the_sample_rate() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, 48_000);
+        let mut out = Vec::new();
+        blip.clock(CLOCK_RATE);
+        blip.read_samples(&mut out);
+        assert_eq!(out.len(), 48_000);
+    }
+
+    #[test]
+    fn partial_samples_carry_over() {
+        // 44.1 kHz is not a whole number of clocks per sample
+        let mut whole = BlipBuffer::new(CLOCK_RATE, 44_100);
+        let mut out = Vec::new();
+        whole.clock(70_224 * 60);
+        whole.read_samples(&mut out);
+        let expected = out.len();
+        assert!((44_100 * 70_224 * 60 / CLOCK_RATE as usize).abs_diff(expected) <= 1);
+
+        let mut frames = BlipBuffer::new(CLOCK_RATE, 44_100);
+        out.clear();
+        for _ in 0..60 {
+            frames.clock(70_224);
+            frames.read_samples(&mut out);
+        }
+        assert_eq!(out.len(), expected);
+    }
+
+    #[test]
+    fn steps_settle_on_the_new_level() {
+        let mut blip = BlipBuffer::new(CLOCK_RATE, 48_000);
+        let mut out = Vec::new();
+        blip.set_amplitude(1.0);
+        blip.clock(CLOCK_RATE / 100);
+        blip.read_samples(&mut out);
+
+        assert!(out[0].abs() < 0.01);
+        assert!(out[out.len() - 1..]
+            .iter()
+            .all(|val| (val - 1.0).abs() < 1e-4));
+    }
+}
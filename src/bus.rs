@@ -1,24 +1,243 @@
+use crate::apu::{Apu, DEFAULT_SAMPLE_RATE};
+use crate::coverage::{self, Coverage};
+use crate::dma::Dma;
+use crate::hdma::{Hdma, HdmaRequest};
+use crate::interrupt;
+use crate::joypad::Joypad;
 use crate::mbc::Mbc;
+use crate::model::Model;
+use crate::ppu::{Mode, Ppu};
 use crate::ram::Ram;
+use crate::sgb::Sgb;
+use crate::timer::Timer;
+use crate::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+use std::cell::RefCell;
+
+// M-cycles the CPU stays stopped after a speed switch
+const SPEED_SWITCH_CYCLES: u16 = 2050;
+// M-cycles (at normal speed) the CPU is halted per 16-byte VRAM DMA block
+const HDMA_BLOCK_CYCLES: u16 = 8;
+
+// Wave RAM left by the boot ROM, random on DMG but commonly this pattern
+const DMG_WAVE_RAM: [u8; 16] = [
+    0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA,
+];
+const CGB_WAVE_RAM: [u8; 16] = [
+    0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF,
+];
+
+/// $4000-$7FFF and $A000-$BFFF, where the MBC switches banks
+pub fn is_switchable(addr: u16) -> bool {
+    return matches!(addr, 0x4000..=0x7FFF | 0xA000..=0xBFFF);
+}
 
 // The bus sits between the CPU and various hardware modules, and routes data reads/writes based on the given address
 
+#[derive(Clone)]
 pub struct Bus {
     mbc: Box<dyn Mbc>,
     ram: Ram,
+    pub ppu: Ppu,
+    pub dma: Dma,
+    pub hdma: Hdma,
+    pub apu: Apu,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    // Present when an SGB runs a game with SGB support
+    pub sgb: Option<Sgb>,
     pub ie: u8,
+    // IF: requested interrupts
+    pub int_flag: u8,
+    pub model: Model,
+    // Game Boy Color features (VRAM/WRAM banks, color palettes)
+    pub cgb: bool,
+    // KEY1: bit 0 (prepare speed switch) and bit 7 (current speed)
+    pub key1_prepare: bool,
+    pub double_speed: bool,
+    // in double speed mode the PPU and APU advance every other M-cycle
+    half_cycle: bool,
+    // Value read from LY instead of the PPU's, Gameboy Doctor logs expect $90
+    pub ly_override: Option<u8>,
+    pub watchpoints: Vec<Watchpoint>,
+    // Reads take &self, so hits are collected behind a RefCell
+    watch_hits: RefCell<Vec<WatchHit>>,
+    // Start of the instruction being executed, reported by watchpoint hits
+    pub instruction_pc: u16,
+    // How ROM bytes were used, while recording coverage
+    coverage: Option<RefCell<Coverage>>,
 }
 
 impl Bus {
     pub fn new(mbc: Box<dyn Mbc>) -> Bus {
         let ram = Ram::new();
         return Bus {
-            mbc: mbc,
-            ram: ram,
+            mbc,
+            ram,
+            ppu: Ppu::new(),
+            dma: Dma::new(),
+            hdma: Hdma::new(),
+            apu: Apu::new(DEFAULT_SAMPLE_RATE),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            sgb: None,
             ie: 0,
+            int_flag: 0,
+            model: Model::Dmg,
+            cgb: false,
+            key1_prepare: false,
+            double_speed: false,
+            half_cycle: false,
+            ly_override: None,
+            watchpoints: Vec::new(),
+            watch_hits: RefCell::new(Vec::new()),
+            instruction_pc: 0,
+            coverage: None,
         };
     }
 
+    pub fn set_cgb_mode(&mut self, cgb: bool) -> () {
+        self.cgb = cgb;
+        self.ppu.cgb = cgb;
+    }
+
+    /// Selects the emulated console and applies the IO state left by its boot ROM
+    /// <https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers>
+    pub fn set_model(&mut self, model: Model, cgb_game: bool) -> () {
+        self.model = model;
+        // CGB hardware runs DMG games in compatibility mode
+        self.set_cgb_mode(model.is_cgb() && cgb_game);
+        self.apu.set_cgb(model.is_cgb());
+
+        self.int_flag = interrupt::VBLANK;
+        // Only known for the DMG boot ROM, the others take a variable time
+        if let Model::Dmg | Model::Mgb = model {
+            self.timer.counter = 0xABCC;
+        }
+
+        self.apu.write(0xFF26, 0x80);
+        self.apu.write(0xFF11, 0x80);
+        self.apu.write(0xFF12, 0xF3);
+        self.apu.write(0xFF25, 0xF3);
+        self.apu.write(0xFF24, 0x77);
+        // The boot sound leaves channel 1 on, silenced by its envelope.
+        // The SGB boot ROM plays no sound
+        self.apu.ch1.enabled = !model.is_sgb();
+        self.apu.ch3.wave_ram = if model.is_cgb() {
+            CGB_WAVE_RAM
+        } else {
+            DMG_WAVE_RAM
+        };
+
+        self.ppu.write(0xFF40, 0x91);
+        self.ppu.write(0xFF47, 0xFC);
+        if self.cgb {
+            // Background palettes are initialized to white
+            for (i, val) in self.ppu.bg_palette.iter_mut().enumerate() {
+                *val = if i % 2 == 0 { 0xFF } else { 0x7F };
+            }
+        }
+    }
+
+    /// Advances the hardware driven by the bus by the given number of CPU M-cycles
+    pub fn tick(&mut self, cycles: u8) -> () {
+        for _ in 0..cycles {
+            self.tick_m_cycle();
+        }
+        self.collect_interrupts();
+
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.update(self.ppu.frame, &self.ppu.framebuffer);
+        }
+    }
+
+    fn tick_m_cycle(&mut self) -> () {
+        // The CPU clock drives OAM DMA and the timer
+        self.tick_dma();
+        self.timer.tick();
+        self.tick_normal_speed();
+
+        if std::mem::take(&mut self.ppu.hblank_started) && self.hdma.hblank_active {
+            self.hdma_transfer_block();
+        }
+    }
+
+    /// The PPU and APU run at the same rate in double speed mode
+    fn tick_normal_speed(&mut self) -> () {
+        self.half_cycle = !self.half_cycle;
+        if self.double_speed && !self.half_cycle {
+            return;
+        }
+        self.ppu.tick();
+        self.apu.tick();
+    }
+
+    fn collect_interrupts(&mut self) -> () {
+        self.int_flag |= std::mem::take(&mut self.ppu.interrupts);
+        self.int_flag |= std::mem::take(&mut self.timer.interrupts);
+        self.int_flag |= std::mem::take(&mut self.joypad.interrupts);
+    }
+
+    /// Executed by STOP while KEY1 bit 0 is set
+    /// <https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch>
+    pub fn switch_speed(&mut self) -> () {
+        self.double_speed = !self.double_speed;
+        self.key1_prepare = false;
+        self.timer.reset_div();
+
+        // DIV does not tick while the CPU is stopped
+        for _ in 0..SPEED_SWITCH_CYCLES {
+            self.tick_normal_speed();
+        }
+        self.collect_interrupts();
+    }
+
+    /// Copies the next 16 bytes of a VRAM DMA while the CPU is halted
+    fn hdma_transfer_block(&mut self) -> () {
+        for i in 0..0x10 {
+            let addr = self.hdma.source.wrapping_add(i);
+            self.cover(addr, coverage::DMA);
+            let val = self.read_mapped(addr);
+            self.ppu
+                .write_vram(0x8000 | (self.hdma.destination + i), val);
+        }
+        self.hdma.advance();
+
+        // The transfer takes the same time in both speeds
+        let cycles = HDMA_BLOCK_CYCLES << self.double_speed as u16;
+        for _ in 0..cycles {
+            self.tick_m_cycle();
+        }
+    }
+
+    fn write_hdma(&mut self, addr: u16, val: u8) -> () {
+        match self.hdma.write(addr, val) {
+            Some(HdmaRequest::GeneralPurpose) => {
+                for _ in 0..=self.hdma.remaining {
+                    self.hdma_transfer_block();
+                }
+            }
+            // The first block is copied right away if the PPU is already in HBlank
+            Some(HdmaRequest::HBlank) => {
+                if !self.ppu.lcd_enabled() || self.ppu.mode == Mode::HBlank {
+                    self.hdma_transfer_block();
+                }
+            }
+            Some(HdmaRequest::Cancel) | None => {}
+        }
+    }
+
+    fn tick_dma(&mut self) -> () {
+        if !self.dma.active {
+            return;
+        }
+        let addr = self.dma.current_addr();
+        self.cover(addr, coverage::DMA);
+        let val = self.read_mapped(addr);
+        let offset = self.dma.advance(val);
+        self.ppu.oam[offset] = val;
+    }
+
     pub fn read_word(&self, addr: u16) -> u16 {
         let low = self.read_byte(addr);
         let high = self.read_byte(addr + 1);
@@ -26,20 +245,64 @@ impl Bus {
         return ((high as u16) << 8) | (low as u16);
     }
 
-    /// https://gbdev.io/pandocs/Memory_Map.html
+    /// Read as seen from the CPU, checked against the watchpoints
     pub fn read_byte(&self, addr: u16) -> u8 {
+        return self.fetch_byte(addr, coverage::DATA);
+    }
+
+    /// Read of the CPU, recorded as `usage` in the coverage.
+    /// Opcode and operand fetches are not data reads and skip the watchpoints
+    pub fn fetch_byte(&self, addr: u16, usage: u8) -> u8 {
+        self.cover(addr, usage);
+        let val = self.peek(addr);
+        let instruction = usage & (coverage::OPCODE | coverage::OPERAND) != 0;
+        if !instruction && !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, val, |kind| kind == WatchKind::Read);
+        }
+        return val;
+    }
+
+    /// Read as seen from the CPU, without triggering watchpoints
+    pub fn peek(&self, addr: u16) -> u8 {
+        if self.dma.blocks(addr) {
+            // OAM is locked by the transfer, everything else conflicts with the DMA read
+            return match addr {
+                0xFE00..=0xFEFF => 0xFF,
+                _ => self.dma.value,
+            };
+        }
+
+        return self.read_mapped(addr);
+    }
+
+    /// https://gbdev.io/pandocs/Memory_Map.html
+    fn read_mapped(&self, addr: u16) -> u8 {
+        if let (0xFF44, Some(ly)) = (addr, self.ly_override) {
+            return ly;
+        }
+
         let val = match addr {
             // mbc
             0x0000..=0x7FFF => self.mbc.read(addr),
             0xA000..=0xBFFF => self.mbc.read(addr),
-            0x8000..=0x9FFF => unimplemented!("unimplemented: ppc 0x{:04X}", addr),
+            // ppu
+            0x8000..=0x9FFF => self.ppu.read_vram(addr),
+            0xFE00..=0xFE9F => self.ppu.oam[(addr - 0xFE00) as usize],
             // ram
-            // TODO: ram側に実装する
-            0xC000..=0xDFFF => self.ram.work[(addr - 0xC000) as usize], //In CGB mode, switchable bank 1~7
-            0xE000..=0xFDFF => self.ram.work[(addr - 0xE000) as usize], // ECHO RAM: Nintendo prohibits developers from using this memory range.
-            0xFF80..=0xFFFE => self.ram.high[(addr - 0xFF80) as usize],
+            0xC000..=0xFDFF | 0xFF80..=0xFFFE => self.ram.read(addr),
 
-            // TODO: ppu
+            // IO
+            0xFF00 => self.joypad.read(),
+            0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF0F => 0xE0 | self.int_flag,
+            0xFF10..=0xFF3F => self.apu.read(addr),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.read(addr),
+            0xFF46 => self.dma.source,
+            0xFF4D if self.cgb => 0x7E | (self.double_speed as u8) << 7 | self.key1_prepare as u8,
+            0xFF4F | 0xFF68..=0xFF6B => self.ppu.read(addr),
+            0xFF51..=0xFF55 if self.cgb => self.hdma.read(addr),
+            0xFF70 if self.cgb => 0xF8 | self.ram.svbk,
+            0xFFFF => self.ie,
 
             // TODO: IO
 
@@ -62,18 +325,52 @@ impl Bus {
     }
 
     pub fn write_byte(&mut self, addr: u16, val: u8) -> () {
+        if self.watchpoints.is_empty() {
+            self.write_unwatched(addr, val);
+            return;
+        }
+
+        let old = self.peek(addr);
+        self.write_unwatched(addr, val);
+        let changed = self.peek(addr) != old;
+        self.check_watchpoints(addr, old, val, |kind| {
+            kind == WatchKind::Write || (kind == WatchKind::Change && changed)
+        });
+    }
+
+    fn write_unwatched(&mut self, addr: u16, val: u8) -> () {
+        // Writes outside HRAM are lost while OAM DMA is running
+        if self.dma.blocks(addr) {
+            return;
+        }
+
         match addr {
             // mbc
             0x0000..=0x7FFF => self.mbc.write(addr, val),
             0xA000..=0xBFFF => self.mbc.write(addr, val),
-            0x8000..=0x9FFF => unimplemented!("unimplemented: ppc 0x{:04X}", addr),
+            // ppu
+            0x8000..=0x9FFF => self.ppu.write_vram(addr, val),
+            0xFE00..=0xFE9F => self.ppu.oam[(addr - 0xFE00) as usize] = val,
             // ram
-            // TODO: ram側に実装する
-            0xC000..=0xDFFF => self.ram.work[(addr - 0xC000) as usize] = val, //In CGB mode, switchable bank 1~7
-            0xE000..=0xFDFF => self.ram.work[(addr - 0xE000) as usize] = val, // ECHO RAM: Nintendo prohibits developers from using this memory range.
-            0xFF80..=0xFFFE => self.ram.high[(addr - 0xFF80) as usize] = val,
+            0xC000..=0xFDFF | 0xFF80..=0xFFFE => self.ram.write(addr, val),
 
-            // TODO: ppu
+            // IO
+            0xFF00 => {
+                self.joypad.write(val);
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_p1(val, &mut self.joypad);
+                }
+            }
+            0xFF04..=0xFF07 => self.timer.write(addr, val),
+            0xFF0F => self.int_flag = val & 0x1F,
+            0xFF10..=0xFF3F => self.apu.write(addr, val),
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => self.ppu.write(addr, val),
+            0xFF46 => self.dma.start(val),
+            0xFF4D if self.cgb => self.key1_prepare = val & 0x01 != 0,
+            0xFF4F | 0xFF68..=0xFF6B => self.ppu.write(addr, val),
+            0xFF51..=0xFF55 if self.cgb => self.write_hdma(addr, val),
+            0xFF70 if self.cgb => self.ram.svbk = val & 0x07,
+            0xFFFF => self.ie = val,
 
             // TODO: IO
 
@@ -84,4 +381,134 @@ impl Bus {
             _ => (),
         };
     }
+
+    /// ROM or cartridge RAM bank mapped at `addr`, None outside the cartridge
+    pub fn bank(&self, addr: u16) -> Option<usize> {
+        return match addr {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(self.mbc.rom_bank()),
+            0xA000..=0xBFFF => Some(self.mbc.ram_bank()),
+            _ => None,
+        };
+    }
+
+    fn check_watchpoints(&self, addr: u16, old: u8, new: u8, matches: impl Fn(WatchKind) -> bool) {
+        let bank = self.bank(addr);
+        for watchpoint in &self.watchpoints {
+            if matches(watchpoint.kind) && watchpoint.contains(addr, bank) {
+                self.watch_hits.borrow_mut().push(WatchHit {
+                    watchpoint: *watchpoint,
+                    pc: self.instruction_pc,
+                    addr,
+                    bank,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+
+    pub fn start_coverage(&mut self, coverage: Coverage) -> () {
+        self.coverage = Some(RefCell::new(coverage));
+    }
+
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        return self.coverage.take().map(RefCell::into_inner);
+    }
+
+    pub fn rom_len(&self) -> usize {
+        return self.mbc.rom_len();
+    }
+
+    fn cover(&self, addr: u16, usage: u8) -> () {
+        if let Some(coverage) = &self.coverage {
+            if let Some(offset) = self.mbc.rom_offset(addr) {
+                coverage.borrow_mut().mark(offset, usage);
+            }
+        }
+    }
+
+    /// Watchpoint hits since the last call
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        return std::mem::take(self.watch_hits.get_mut());
+    }
+
+    /// Copy of the hardware state, without the watchpoints and coverage
+    pub fn snapshot(&mut self) -> Bus {
+        let watchpoints = std::mem::take(&mut self.watchpoints);
+        let coverage = self.coverage.take();
+        let mut snapshot = self.clone();
+        snapshot.take_watch_hits();
+        self.watchpoints = watchpoints;
+        self.coverage = coverage;
+        return snapshot;
+    }
+
+    /// Goes back to a snapshot, keeping the current watchpoints and coverage
+    pub fn restore(&mut self, snapshot: &Bus) -> () {
+        let watchpoints = std::mem::take(&mut self.watchpoints);
+        let coverage = self.coverage.take();
+        *self = snapshot.clone();
+        self.watchpoints = watchpoints;
+        self.coverage = coverage;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::new_mbc;
+    use crate::rom::tests::rom_with_code;
+
+    fn bus_with(watchpoint: Watchpoint) -> Bus {
+        let mut bus = Bus::new(new_mbc(rom_with_code(&[])));
+        bus.watchpoints.push(watchpoint);
+        return bus;
+    }
+
+    #[test]
+    fn change_only_fires_on_a_new_value() {
+        let mut bus = bus_with(Watchpoint::new(0xC000, 0xC000, WatchKind::Change));
+        bus.write_byte(0xC000, 0x05);
+        let hits = bus.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].old, hits[0].new), (0x00, 0x05));
+
+        bus.write_byte(0xC000, 0x05);
+        assert!(bus.take_watch_hits().is_empty());
+
+        bus.watchpoints[0].kind = WatchKind::Write;
+        bus.write_byte(0xC000, 0x05);
+        assert_eq!(bus.take_watch_hits().len(), 1);
+    }
+
+    #[test]
+    fn banked_watchpoint_needs_its_bank_mapped() {
+        let mut bus = bus_with(Watchpoint::new(0x4000, 0x4FFF, WatchKind::Read).with_bank(2));
+        bus.read_byte(0x4100);
+        assert!(bus.take_watch_hits().is_empty());
+
+        // without banking, bank 1 is always at $4000-$7FFF
+        bus.watchpoints[0] = bus.watchpoints[0].with_bank(1);
+        bus.read_byte(0x4100);
+        let hits = bus.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].bank, Some(1));
+
+        // WRAM has no bank to match
+        bus.watchpoints[0] = Watchpoint::new(0xC000, 0xC000, WatchKind::Read).with_bank(0);
+        bus.read_byte(0xC000);
+        assert!(bus.take_watch_hits().is_empty());
+    }
+
+    #[test]
+    fn instruction_fetches_are_not_reads() {
+        let mut bus = bus_with(Watchpoint::new(0x0100, 0x0102, WatchKind::Read));
+        bus.fetch_byte(0x0100, coverage::OPCODE);
+        bus.fetch_byte(0x0101, coverage::OPERAND);
+        assert!(bus.take_watch_hits().is_empty());
+
+        bus.fetch_byte(0x0102, coverage::DATA);
+        assert_eq!(bus.take_watch_hits().len(), 1);
+    }
 }
@@ -0,0 +1,208 @@
+use crate::rom::Rom;
+
+/// Colorization picked by the CGB boot ROM for DMG games
+/// <https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes>
+///
+/// Games licensed by Nintendo are looked up by the sum of their title bytes,
+/// checksums shared by several games are told apart by the 4th title letter.
+/// Everything else gets the default palette.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatPalettes {
+    // RGB555 colors for shades 0-3
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// Checksums after the first 65 are only used when the 4th letter matches too
+const UNIQUE_CHECKSUMS: usize = 65;
+
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3,
+];
+
+// 4th title letter of the games sharing a checksum
+const DUPLICATE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Index into PALETTE_COMBINATIONS for each checksum
+#[rustfmt::skip]
+const PALETTE_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18,
+    29,
+];
+
+// Offsets in colors into PALETTES for OBJ0, OBJ1 and BG.
+// A few games use palettes that start in the middle of another one.
+#[rustfmt::skip]
+const PALETTE_COMBINATIONS: [[usize; 3]; 51] = [
+    [4 * 4, 4 * 4, 29 * 4],
+    [18 * 4, 18 * 4, 18 * 4],
+    [20 * 4, 20 * 4, 20 * 4],
+    [24 * 4, 24 * 4, 24 * 4],
+    [9 * 4, 9 * 4, 9 * 4],
+    [0, 0, 0],
+    [27 * 4, 27 * 4, 27 * 4],
+    [5 * 4, 5 * 4, 5 * 4],
+    [12 * 4, 12 * 4, 12 * 4],
+    [26 * 4, 26 * 4, 26 * 4],
+    [16 * 4, 8 * 4, 8 * 4],
+    [4 * 4, 28 * 4, 28 * 4],
+    [4 * 4, 2 * 4, 2 * 4],
+    [3 * 4, 4 * 4, 4 * 4],
+    [4 * 4, 29 * 4, 29 * 4],
+    [28 * 4, 4 * 4, 28 * 4],
+    [2 * 4, 17 * 4, 2 * 4],
+    [16 * 4, 16 * 4, 8 * 4],
+    [4 * 4, 4 * 4, 7 * 4],
+    [4 * 4, 4 * 4, 18 * 4],
+    [4 * 4, 4 * 4, 20 * 4],
+    [19 * 4, 19 * 4, 9 * 4],
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    [17 * 4, 17 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 3 * 4],
+    [28 * 4, 28 * 4, 0],
+    [3 * 4, 3 * 4, 0],
+    [0, 0, 4],
+    [18 * 4, 22 * 4, 18 * 4],
+    [20 * 4, 22 * 4, 20 * 4],
+    [24 * 4, 22 * 4, 24 * 4],
+    [16 * 4, 22 * 4, 8 * 4],
+    [17 * 4, 4 * 4, 13 * 4],
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    [19 * 4, 22 * 4, 9 * 4],
+    [16 * 4, 28 * 4, 10 * 4],
+    [4 * 4, 23 * 4, 28 * 4],
+    [17 * 4, 22 * 4, 2 * 4],
+    [4 * 4, 0, 2 * 4],
+    [4 * 4, 28 * 4, 3 * 4],
+    [28 * 4, 3 * 4, 0],
+    [3 * 4, 28 * 4, 4 * 4],
+    [21 * 4, 28 * 4, 4 * 4],
+    [3 * 4, 28 * 4, 0],
+    [25 * 4, 3 * 4, 28 * 4],
+    [0, 28 * 4, 8 * 4],
+    [4 * 4, 3 * 4, 28 * 4],
+    [28 * 4, 3 * 4, 6 * 4],
+    [4 * 4, 28 * 4, 29 * 4],
+];
+
+#[rustfmt::skip]
+const PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+impl CompatPalettes {
+    pub fn for_rom(rom: &Rom) -> CompatPalettes {
+        let [obj0, obj1, bg] = PALETTE_COMBINATIONS[palette_index(rom) as usize];
+        return CompatPalettes {
+            bg: palette_at(bg),
+            obj0: palette_at(obj0),
+            obj1: palette_at(obj1),
+        };
+    }
+}
+
+/// Index into PALETTE_COMBINATIONS, 0 when the game is not in the table
+fn palette_index(rom: &Rom) -> u8 {
+    if !rom.licensed_by_nintendo() {
+        return 0;
+    }
+
+    let checksum = rom.title_checksum();
+    let letter = rom.title[3];
+    let found = (0..TITLE_CHECKSUMS.len()).find(|&i| {
+        TITLE_CHECKSUMS[i] == checksum
+            && (i < UNIQUE_CHECKSUMS || DUPLICATE_LETTERS[i - UNIQUE_CHECKSUMS] == letter)
+    });
+    return match found {
+        Some(i) => PALETTE_PER_CHECKSUM[i],
+        None => 0,
+    };
+}
+
+fn palette_at(offset: usize) -> [u16; 4] {
+    let mut palette = [0; 4];
+    palette.copy_from_slice(&PALETTES[offset..offset + 4]);
+    return palette;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::rom_with_title;
+
+    const DEFAULT_BG: [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+
+    #[test]
+    fn tetris() {
+        let palettes = CompatPalettes::for_rom(&rom_with_title(b"TETRIS", 0x01));
+        let yellow_red = [0x7FFF, 0x03FF, 0x001F, 0x0000];
+        assert_eq!(palettes.bg, yellow_red);
+        assert_eq!(palettes.obj0, yellow_red);
+        assert_eq!(palettes.obj1, yellow_red);
+    }
+
+    #[test]
+    fn pokemon_red() {
+        let palettes = CompatPalettes::for_rom(&rom_with_title(b"POKEMON RED", 0x01));
+        assert_eq!(palettes.bg, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(palettes.obj0, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+        assert_eq!(palettes.obj1, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+    }
+
+    #[test]
+    fn other_licensees_get_the_default() {
+        let palettes = CompatPalettes::for_rom(&rom_with_title(b"POKEMON RED", 0x08));
+        assert_eq!(palettes.bg, DEFAULT_BG);
+        assert_eq!(palettes.obj0, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+
+        // and so do unknown Nintendo titles
+        let unknown = CompatPalettes::for_rom(&rom_with_title(b"UNKNOWN", 0x01));
+        assert_eq!(unknown, palettes);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+
+// Flags of a ROM byte, combined when it was used several ways
+pub const OPCODE: u8 = 0x01;
+pub const OPERAND: u8 = 0x02;
+pub const DATA: u8 = 0x04;
+// source of an OAM DMA or VRAM DMA transfer
+pub const DMA: u8 = 0x08;
+
+/// Code/data log: how each ROM byte was used, indexed by ROM offset
+///
+/// Saved as one flag byte per ROM byte, like the CDL files of other emulators,
+/// so coverage from several sessions can be merged with `load`.
+#[derive(Clone)]
+pub struct Coverage {
+    pub flags: Vec<u8>,
+}
+
+/// Number of ROM bytes with each flag
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub opcode: usize,
+    pub operand: usize,
+    pub data: usize,
+    pub dma: usize,
+    pub unused: usize,
+}
+
+impl Coverage {
+    pub fn new(rom_len: usize) -> Coverage {
+        return Coverage {
+            flags: vec![0; rom_len],
+        };
+    }
+
+    /// Continues a saved log, which has to be of a ROM of the same size
+    pub fn load(path: &str, rom_len: usize) -> anyhow::Result<Coverage> {
+        let flags = fs::read(path)?;
+        if flags.len() != rom_len {
+            anyhow::bail!(
+                "{} covers {} bytes but the ROM has {}",
+                path,
+                flags.len(),
+                rom_len
+            );
+        }
+        return Ok(Coverage { flags });
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        fs::write(path, &self.flags)?;
+        return Ok(());
+    }
+
+    pub fn mark(&mut self, offset: usize, flag: u8) -> () {
+        if let Some(flags) = self.flags.get_mut(offset) {
+            *flags |= flag;
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        for flags in &self.flags {
+            summary.opcode += (flags & OPCODE != 0) as usize;
+            summary.operand += (flags & OPERAND != 0) as usize;
+            summary.data += (flags & DATA != 0) as usize;
+            summary.dma += (flags & DMA != 0) as usize;
+            summary.unused += (*flags == 0) as usize;
+        }
+        return summary;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::Gb;
+    use crate::model::Model;
+    use crate::rom::tests::rom_with_code;
+
+    #[test]
+    fn marks_opcodes_operands_and_data() {
+        // ld a, [$0150] then, at $0104, ld a, $07
+        let code = [0xFA, 0x50, 0x01, 0x00, 0x3E, 0x07];
+        let mut gb = Gb::from_rom(rom_with_code(&code), Some(Model::Dmg));
+        gb.start_coverage(None);
+        gb.step();
+        gb.cpu_mut().registers.pc = 0x0104;
+        gb.step();
+        let coverage = gb.stop_coverage().unwrap();
+
+        assert_eq!(coverage.flags.len(), 0x8000);
+        assert_eq!(
+            coverage.flags[0x0100..0x0107],
+            [OPCODE, OPERAND, OPERAND, 0, OPCODE, OPERAND, 0]
+        );
+        assert_eq!(coverage.flags[0x0150], DATA);
+        assert_eq!(
+            coverage.summary(),
+            Summary {
+                opcode: 2,
+                operand: 3,
+                data: 1,
+                dma: 0,
+                unused: 0x8000 - 6,
+            }
+        );
+    }
+
+    #[test]
+    fn flags_of_several_uses_combine() {
+        let mut coverage = Coverage::new(4);
+        coverage.mark(1, OPCODE);
+        coverage.mark(1, DATA);
+        coverage.mark(4, DATA);
+        assert_eq!(coverage.flags, [0, OPCODE | DATA, 0, 0]);
+    }
+}
@@ -1,4 +1,8 @@
 use crate::bus::Bus;
+use crate::coverage;
+use crate::model::BootRegisters;
+use crate::profiler::Profiler;
+use crate::trace::Trace;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
@@ -9,6 +13,7 @@ use num_traits::FromPrimitive;
 /// HL     HL
 /// SP     Stack Pointer
 /// PC     Program Counter/Pointer
+#[derive(Clone)]
 pub struct Registers {
     pub a: u8,
     pub f: u8,
@@ -156,7 +161,7 @@ impl Registers {
 
     fn set_b(&mut self, val: u8) {
         self.bc &= 0x00FF;
-        self.bc |= ((val as u16) << 8) as u16;
+        self.bc |= (val as u16) << 8;
     }
 
     fn set_c(&mut self, val: u8) {
@@ -166,7 +171,7 @@ impl Registers {
 
     fn set_d(&mut self, val: u8) {
         self.de &= 0x00FF;
-        self.de |= ((val as u16) << 8) as u16;
+        self.de |= (val as u16) << 8;
     }
 
     fn set_e(&mut self, val: u8) {
@@ -176,7 +181,7 @@ impl Registers {
 
     fn set_h(&mut self, val: u8) {
         self.hl &= 0x00FF;
-        self.hl |= ((val as u16) << 8) as u16;
+        self.hl |= (val as u16) << 8;
     }
 
     fn set_l(&mut self, val: u8) {
@@ -230,11 +235,19 @@ impl Registers {
     }
 }
 
+impl Default for Registers {
+    fn default() -> Registers {
+        return Registers::new();
+    }
+}
+
 /// Bit	Name Explanation
 /// 7	z    Zero flag
 /// 6	n    Subtraction flag (BCD)
 /// 5	h    Half Carry flag (BCD)
 /// 4	c    Carry flag
+#[allow(clippy::tabs_in_doc_comments)]
+#[derive(Clone)]
 pub struct FlagsRegisters {
     pub z: bool,
     pub n: bool,
@@ -252,6 +265,14 @@ impl FlagsRegisters {
         }
     }
 
+    /// The flags as stored in the F register
+    pub fn bits(&self) -> u8 {
+        return (self.z as u8) << 7
+            | (self.n as u8) << 6
+            | (self.h as u8) << 5
+            | (self.c as u8) << 4;
+    }
+
     fn get_c(&self, cc: Cc) -> bool {
         match cc {
             Cc::Nz => !self.z,
@@ -278,16 +299,73 @@ impl FlagsRegisters {
     }
 }
 
+impl Default for FlagsRegisters {
+    fn default() -> FlagsRegisters {
+        return FlagsRegisters::new();
+    }
+}
+
+/// Machine cycles taken by each unprefixed opcode.
+/// Conditional jumps/calls/returns list the not-taken cost, the branch adds the rest.
+/// <https://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html>
+#[rustfmt::skip]
+pub(crate) const OPCODE_CYCLES: [u8; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0x00
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 0x10
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 0x20
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 0x30
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x40
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x50
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x60
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1, // 0x70
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x80
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x90
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0xA0
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0xB0
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4, // 0xC0
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4, // 0xD0
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4, // 0xE0
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4, // 0xF0
+];
+
+/// Machine cycles taken by a CB-prefixed opcode, including the prefix
+pub(crate) fn prefixed_cycles(opcode: u8) -> u8 {
+    // (HL) operands need extra memory accesses, BIT only reads
+    return match (opcode >> 6, opcode & 0x07) {
+        (1, 6) => 3,
+        (_, 6) => 4,
+        _ => 2,
+    };
+}
+
+/// CPU and hardware state to resume from, without the trace and profiler
+#[derive(Clone)]
+pub struct Snapshot {
+    registers: Registers,
+    flag_registers: FlagsRegisters,
+    clock_cycles_wait: u8,
+    cycles: u8,
+    halt: bool,
+    ime: bool,
+    bus: Bus,
+}
+
 pub struct Cpu {
     pub registers: Registers,
     pub flag_registers: FlagsRegisters,
 
     pub clock_cycles_wait: u8,
+    // M-cycles spent by the instruction being executed
+    cycles: u8,
 
     halt: bool,
     ime: bool,
 
-    bus: Bus,
+    pub bus: Bus,
+    // Receives one line per instruction when tracing is on
+    pub trace: Option<Trace>,
+    // Counts cycles per address while profiling
+    pub profiler: Option<Profiler>,
 }
 
 impl Cpu {
@@ -297,34 +375,156 @@ impl Cpu {
             registers: Registers::new(),
             flag_registers: FlagsRegisters::new(),
             clock_cycles_wait: 0,
+            cycles: 0,
 
             halt: false,
             ime: false,
 
-            bus: bus,
+            bus,
+            trace: None,
+            profiler: None,
+        };
+    }
+
+    /// Starts after the boot ROM, at the cartridge entry point
+    pub fn set_boot_registers(&mut self, boot: BootRegisters) -> () {
+        self.registers.set_af(boot.af);
+        self.registers.bc = boot.bc;
+        self.registers.de = boot.de;
+        self.registers.hl = boot.hl;
+        self.registers.sp = 0xFFFE;
+        self.registers.pc = 0x0100;
+        self.set_flags(self.registers.f);
+    }
+
+    /// Sets F, keeping the flag registers in sync
+    pub fn set_flags(&mut self, f: u8) -> () {
+        self.registers.f = f & 0xF0;
+        self.flag_registers.z = f & 0x80 != 0;
+        self.flag_registers.n = f & 0x40 != 0;
+        self.flag_registers.h = f & 0x20 != 0;
+        self.flag_registers.c = f & 0x10 != 0;
+    }
+
+    /// Interrupt master enable
+    pub fn ime(&self) -> bool {
+        return self.ime;
+    }
+
+    pub fn snapshot(&mut self) -> Snapshot {
+        return Snapshot {
+            registers: self.registers.clone(),
+            flag_registers: self.flag_registers.clone(),
+            clock_cycles_wait: self.clock_cycles_wait,
+            cycles: self.cycles,
+            halt: self.halt,
+            ime: self.ime,
+            bus: self.bus.snapshot(),
         };
     }
 
+    /// Goes back to a snapshot, tracing and profiling carry on from there
+    pub fn restore(&mut self, snapshot: &Snapshot) -> () {
+        self.registers = snapshot.registers.clone();
+        self.flag_registers = snapshot.flag_registers.clone();
+        self.clock_cycles_wait = snapshot.clock_cycles_wait;
+        self.cycles = snapshot.cycles;
+        self.halt = snapshot.halt;
+        self.ime = snapshot.ime;
+        self.bus.restore(&snapshot.bus);
+    }
+
     fn get_n(&mut self) -> u8 {
-        let byte = self.bus.read_byte(self.registers.pc);
+        let byte = self.bus.fetch_byte(self.registers.pc, coverage::OPERAND);
         self.registers.pc += 1;
 
         byte
     }
 
     fn get_nn(&mut self) -> u16 {
-        let word = self.bus.read_word(self.registers.pc);
-        self.registers.pc += 2;
+        let low = self.get_n();
+        let high = self.get_n();
 
-        word
+        u16::from_le_bytes([low, high])
     }
 
-    pub fn step(&mut self) -> () {
-        let opcode = self.get_n();
-        println!("opcode: {:02X}, pc: {:02X}", opcode, self.registers.pc);
+    fn get_opcode(&mut self) -> u8 {
+        let byte = self.bus.fetch_byte(self.registers.pc, coverage::OPCODE);
+        self.registers.pc += 1;
+
+        byte
+    }
+
+    /// Runs the next instruction, or only dispatches an interrupt or idles in HALT,
+    /// returning whether an instruction was executed
+    pub fn step(&mut self) -> bool {
+        if self.interrupt_or_idle() {
+            return false;
+        }
+
+        if let Some(mut trace) = self.trace.take() {
+            trace.log(self);
+            self.trace = Some(trace);
+        }
+
+        let pc = self.registers.pc;
+        self.bus.instruction_pc = pc;
+        // before the instruction can switch banks
+        let bank = self.profiler.as_ref().map(|_| self.bus.bank(pc));
+        let opcode = self.get_opcode();
+        self.cycles = OPCODE_CYCLES[opcode as usize];
         self.call_operation(opcode);
 
         self.registers.pc += 1;
+
+        if let (Some(profiler), Some(bank)) = (self.profiler.as_mut(), bank) {
+            let next = self.registers.pc;
+            profiler.record((bank, pc), opcode, self.cycles, (self.bus.bank(next), next));
+        }
+
+        self.bus.tick(self.cycles);
+        return true;
+    }
+
+    /// Dispatches a pending interrupt or idles a cycle in HALT, returning false when
+    /// neither happened and the instruction at PC runs next
+    pub fn interrupt_or_idle(&mut self) -> bool {
+        if self.dispatch_interrupt() {
+            return true;
+        }
+        if self.halt {
+            self.bus.tick(1);
+            return true;
+        }
+        return false;
+    }
+
+    /// Jumps to the vector of the highest priority interrupt that is enabled and requested.
+    /// A requested interrupt also ends HALT when IME is off, without being serviced
+    /// <https://gbdev.io/pandocs/Interrupts.html>
+    fn dispatch_interrupt(&mut self) -> bool {
+        let pending = self.bus.ie & self.bus.int_flag & 0x1F;
+        if pending == 0 {
+            return false;
+        }
+        self.halt = false;
+        if !self.ime {
+            return false;
+        }
+
+        let bit = pending.trailing_zeros() as u16;
+        self.ime = false;
+        self.bus.int_flag &= !(1 << bit);
+        let vector = 0x40 + bit * 8;
+        self.registers.sp = self.registers.sp.wrapping_sub(2);
+        self.bus.write_word(self.registers.sp, self.registers.pc);
+        self.registers.pc = vector;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.interrupt((self.bus.bank(vector), vector));
+        }
+        // two wait states, the push and the jump
+        self.bus.tick(5);
+        return true;
     }
 
     fn call_operation(&mut self, opcode: u8) {
@@ -337,7 +537,6 @@ impl Cpu {
 
         // xx yyy zzz
         //    ppq
-        println!("x: {:b}, y: {:b}, z: {:b}, p: {:b}, q: {:b}", x, y, z, p, q);
         match x {
             0 => match z {
                 0 => match y {
@@ -488,7 +687,7 @@ impl Cpu {
                     0 => self.jp_nn(),
                     1 => {
                         //self.cb_prefix();
-                        let prefixed_opcode = self.get_n();
+                        let prefixed_opcode = self.get_opcode();
                         self.call_prefixed_operation(prefixed_opcode);
                     }
                     6 => self.di(),
@@ -541,6 +740,8 @@ impl Cpu {
         let y = opcode << 2 >> 5;
         let z = opcode << 5 >> 5;
 
+        self.cycles = prefixed_cycles(opcode);
+
         match x {
             0 => {
                 let rot = Rot::from_u8(y).unwrap();
@@ -579,7 +780,12 @@ impl Cpu {
         self.bus.write_word(nn, self.registers.sp);
     }
 
-    fn stop(&mut self) {}
+    /// On CGB, STOP with KEY1 bit 0 set switches between normal and double speed
+    fn stop(&mut self) {
+        if self.bus.cgb && self.bus.key1_prepare {
+            self.bus.switch_speed();
+        }
+    }
 
     fn jr_d(&mut self) {
         let d = self.get_n();
@@ -597,6 +803,7 @@ impl Cpu {
 
         if val {
             self.registers.pc = self.registers.pc.wrapping_add(d as u16);
+            self.cycles += 1;
         }
     }
 
@@ -958,6 +1165,7 @@ impl Cpu {
         if val {
             let nn = self.get_nn();
             self.registers.pc = nn;
+            self.cycles += 3;
         }
     }
 
@@ -969,7 +1177,7 @@ impl Cpu {
 
     fn add_sp_d(&mut self) {
         let left = self.registers.sp;
-        let right = self.bus.read_byte(self.registers.pc) as i8 as u16;
+        let right = self.bus.fetch_byte(self.registers.pc, coverage::OPERAND) as i8 as u16;
         let result = left.wrapping_add(right);
 
         self.registers.sp = result;
@@ -1023,6 +1231,7 @@ impl Cpu {
         if val {
             let nn = self.get_nn();
             self.registers.pc = nn;
+            self.cycles += 1;
         }
     }
 
@@ -1052,7 +1261,10 @@ impl Cpu {
     }
 
     fn jp_nn(&mut self) {
-        let addr = self.bus.read_word(self.registers.pc);
+        let pc = self.registers.pc;
+        let low = self.bus.fetch_byte(pc, coverage::OPERAND);
+        let high = self.bus.fetch_byte(pc.wrapping_add(1), coverage::OPERAND);
+        let addr = u16::from_le_bytes([low, high]);
         self.registers.pc = addr;
     }
 
@@ -1071,6 +1283,7 @@ impl Cpu {
             let addr = self.get_nn();
             self.bus.write_word(self.registers.sp, self.registers.pc);
             self.registers.pc = addr;
+            self.cycles += 3;
         }
     }
 
@@ -0,0 +1,696 @@
+use crate::bus::{is_switchable, Bus};
+use crate::disasm::{decode_bus, Instruction};
+use crate::gb::Gb;
+use crate::symbols::Symbols;
+use crate::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::fmt::Write;
+
+// Executed instructions kept to show what led to PC
+const HISTORY_LEN: usize = 3;
+const DEFAULT_DISASSEMBLY_LEN: usize = 6;
+const DEFAULT_DUMP_LEN: u16 = 0x40;
+
+/// Why execution stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(Breakpoint),
+    Watchpoint(Vec<WatchHit>),
+    // `continue` ran for the given number of instructions
+    Limit,
+}
+
+/// A PC breakpoint, only while `bank` is mapped when given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub bank: Option<usize>,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Breakpoint {
+        return Breakpoint { addr, bank: None };
+    }
+
+    pub fn matches(&self, bus: &Bus, pc: u16) -> bool {
+        return pc == self.addr && (self.bank.is_none() || self.bank == bus.bank(pc));
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.bank {
+            Some(bank) => write!(f, "${:02X}:{:04X}", bank, self.addr),
+            None => write!(f, "${:04X}", self.addr),
+        };
+    }
+}
+
+/// A CALL, RST or interrupt that has not returned yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub call_site: u16,
+    pub target: u16,
+    pub return_addr: u16,
+    // mapped at the call site and the target when the call was made
+    pub bank: Option<usize>,
+    pub target_bank: Option<usize>,
+}
+
+// How an instruction changed the call stack, to undo when going back
+#[derive(Debug, Clone, Copy)]
+enum StackChange {
+    Call,
+    Return(Frame),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    // stops after the given number of instructions if no breakpoint is hit
+    Continue(Option<usize>),
+    // undoes the given number of instructions
+    Back(u64),
+    // goes back the given number of frames
+    Rewind(u64),
+    Break(Breakpoint),
+    Delete(Breakpoint),
+    Breakpoints,
+    Watch(Watchpoint),
+    // index in the watchpoint list
+    Unwatch(usize),
+    Watchpoints,
+    Registers,
+    Set(String, u16),
+    Dump(u16, u16),
+    Disassemble(Option<u16>, usize),
+    Backtrace,
+    Help,
+    Quit,
+}
+
+const HELP: &str = "\
+s, step [N]            execute N instructions
+c, continue [N]        run until a breakpoint, or for N instructions
+back [N]               undo the last N instructions
+rewind N               go back N frames
+b, break LOC           add a PC breakpoint
+delete LOC             remove a breakpoint
+breakpoints            list breakpoints
+watch r|w|c LOC[-END]  pause on reads, writes or value changes
+unwatch N              remove watchpoint N
+watchpoints            list watchpoints
+r, regs                show registers and flags
+set REG VALUE          set a/f/b/c/d/e/h/l, af/bc/de/hl/sp/pc or a flag z/n/hf/cy
+x LOC [LEN]            hex dump memory
+d, disasm [LOC] [N]    disassemble N instructions, around PC by default
+bt, backtrace          show the call stack
+q, quit                exit
+Numbers are hexadecimal, with an optional $ or 0x prefix.
+LOC is a label or [BANK:]ADDR, labels in $4000-$7FFF and $A000-$BFFF keep their bank.";
+
+impl Command {
+    /// Addresses can be given as labels of `symbols`
+    pub fn parse(line: &str, symbols: Option<&Symbols>) -> anyhow::Result<Command> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        let arg = |i: usize| -> anyhow::Result<u16> {
+            match args.get(i) {
+                Some(word) => parse_number(word),
+                None => anyhow::bail!("missing argument for {}", name),
+            }
+        };
+        let location = |i: usize| -> anyhow::Result<(Option<usize>, u16)> {
+            match args.get(i) {
+                Some(word) => parse_location(word, symbols),
+                None => anyhow::bail!("missing argument for {}", name),
+            }
+        };
+        let breakpoint = |i: usize| -> anyhow::Result<Breakpoint> {
+            let (bank, addr) = location(i)?;
+            return Ok(Breakpoint { addr, bank });
+        };
+        let count = |i: usize| -> anyhow::Result<Option<usize>> {
+            return args.get(i).map(|word| Ok(word.parse()?)).transpose();
+        };
+        let frames = |i: usize| -> anyhow::Result<Option<u64>> {
+            return args.get(i).map(|word| Ok(word.parse()?)).transpose();
+        };
+
+        let command = match name {
+            "s" | "step" => Command::Step(count(0)?.unwrap_or(1)),
+            "c" | "continue" => Command::Continue(count(0)?),
+            "back" => Command::Back(frames(0)?.unwrap_or(1)),
+            "rewind" => match frames(0)? {
+                Some(frames) => Command::Rewind(frames),
+                None => anyhow::bail!("missing argument for rewind"),
+            },
+            "b" | "break" => Command::Break(breakpoint(0)?),
+            "delete" => Command::Delete(breakpoint(0)?),
+            "breakpoints" => Command::Breakpoints,
+            "watch" => {
+                let kind = args.first().and_then(|s| WatchKind::parse(s));
+                let kind = kind.ok_or_else(|| anyhow::anyhow!("watch needs r, w or c"))?;
+                match args.get(1) {
+                    Some(range) => Command::Watch(parse_watchpoint(range, kind, symbols)?),
+                    None => anyhow::bail!("missing argument for watch"),
+                }
+            }
+            "unwatch" => match count(0)? {
+                Some(i) => Command::Unwatch(i),
+                None => anyhow::bail!("missing argument for unwatch"),
+            },
+            "watchpoints" => Command::Watchpoints,
+            "r" | "regs" => Command::Registers,
+            "set" => {
+                let register = args.first().map(|s| s.to_ascii_lowercase());
+                Command::Set(register.unwrap_or_default(), arg(1)?)
+            }
+            "x" => Command::Dump(
+                location(0)?.1,
+                args.get(1)
+                    .map_or(Ok(DEFAULT_DUMP_LEN), |s| parse_number(s))?,
+            ),
+            "d" | "disasm" => Command::Disassemble(
+                match args.first() {
+                    Some(word) => Some(parse_location(word, symbols)?.1),
+                    None => None,
+                },
+                count(1)?.unwrap_or(DEFAULT_DISASSEMBLY_LEN),
+            ),
+            "bt" | "backtrace" => Command::Backtrace,
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => anyhow::bail!("unknown command: {} (try help)", name),
+        };
+        return Ok(command);
+    }
+}
+
+/// Hexadecimal, with an optional `$` or `0x` prefix
+pub fn parse_number(word: &str) -> anyhow::Result<u16> {
+    let hex = word.trim_start_matches('$').trim_start_matches("0x");
+    return Ok(u16::from_str_radix(hex, 16)?);
+}
+
+/// A label, or `[BANK:]ADDR`. Labels keep their bank in the switchable windows
+pub fn parse_location(
+    word: &str,
+    symbols: Option<&Symbols>,
+) -> anyhow::Result<(Option<usize>, u16)> {
+    if let Some((bank, addr)) = symbols.and_then(|symbols| symbols.address_of(word)) {
+        return Ok((Some(bank).filter(|_| is_switchable(addr)), addr));
+    }
+    return match word.split_once(':') {
+        Some((bank, addr)) => Ok((Some(parse_number(bank)? as usize), parse_number(addr)?)),
+        None => Ok((None, parse_number(word)?)),
+    };
+}
+
+/// `LOC[-END]`, e.g. `C000`, `C000-C0FF`, `02:4000-7FFF` or `wScore`
+pub fn parse_watchpoint(
+    word: &str,
+    kind: WatchKind,
+    symbols: Option<&Symbols>,
+) -> anyhow::Result<Watchpoint> {
+    let (start, end) = match word.split_once('-') {
+        Some((start, end)) => (start, Some(end)),
+        None => (word, None),
+    };
+    let (bank, start) = parse_location(start, symbols)?;
+    let end = match end {
+        Some(end) => parse_location(end, symbols)?.1,
+        None => start,
+    };
+    if end < start {
+        anyhow::bail!("empty range: {}", word);
+    }
+
+    let watchpoint = Watchpoint::new(start, end, kind);
+    return Ok(match bank {
+        Some(bank) => watchpoint.with_bank(bank),
+        None => watchpoint,
+    });
+}
+
+/// Steps a `Gb` one instruction at a time, tracking breakpoints and calls.
+/// Going back needs rewinding to be enabled on the `Gb`
+pub struct Debugger {
+    pub gb: Gb,
+    pub breakpoints: BTreeSet<Breakpoint>,
+    pub call_stack: Vec<Frame>,
+    history: VecDeque<u16>,
+    // with the instruction count, as far back as the rewind buffer goes
+    stack_changes: VecDeque<(u64, StackChange)>,
+}
+
+impl Debugger {
+    pub fn new(gb: Gb) -> Debugger {
+        return Debugger {
+            gb,
+            breakpoints: BTreeSet::new(),
+            call_stack: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_LEN + 1),
+            stack_changes: VecDeque::new(),
+        };
+    }
+
+    pub fn pc(&self) -> u16 {
+        return self.gb.cpu().registers.pc;
+    }
+
+    pub fn decode(&self, addr: u16) -> Instruction {
+        return decode_bus(&self.gb.cpu().bus, addr);
+    }
+
+    /// Executes one instruction and follows CALL/RST, interrupts and RET.
+    /// Returns the watchpoints it hit
+    pub fn step(&mut self) -> Vec<WatchHit> {
+        let instruction = self.decode(self.pc());
+        let count = self.gb.instructions();
+        if !self.gb.step() {
+            // PC only moves without an instruction when an interrupt is dispatched
+            let pc = self.pc();
+            if pc != instruction.address {
+                let bus = &self.gb.cpu().bus;
+                self.call_stack.push(Frame {
+                    call_site: instruction.address,
+                    target: pc,
+                    return_addr: instruction.address,
+                    bank: bus.bank(instruction.address),
+                    target_bank: bus.bank(pc),
+                });
+                self.log_stack_change(count, StackChange::Call);
+            }
+            return self.gb.take_watch_hits();
+        }
+
+        let pc = self.pc();
+        let fallthrough = instruction.address.wrapping_add(instruction.length());
+        let mnemonic = instruction.mnemonic.as_str();
+        // conditional ones only count when taken
+        if pc != fallthrough {
+            if mnemonic.starts_with("call") || mnemonic.starts_with("rst") {
+                let bus = &self.gb.cpu().bus;
+                self.call_stack.push(Frame {
+                    call_site: instruction.address,
+                    target: pc,
+                    return_addr: fallthrough,
+                    bank: bus.bank(instruction.address),
+                    target_bank: bus.bank(pc),
+                });
+                self.log_stack_change(count, StackChange::Call);
+            } else if mnemonic.starts_with("ret") {
+                if let Some(frame) = self.call_stack.pop() {
+                    self.log_stack_change(count, StackChange::Return(frame));
+                }
+            }
+        }
+
+        self.history.push_back(instruction.address);
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+        return self.gb.take_watch_hits();
+    }
+
+    /// Runs up to `count` instructions, stopping early on a watchpoint
+    pub fn step_n(&mut self, count: usize) -> StopReason {
+        for _ in 0..count {
+            let hits = self.step();
+            if !hits.is_empty() {
+                return StopReason::Watchpoint(hits);
+            }
+        }
+        return StopReason::Step;
+    }
+
+    /// Runs until a breakpoint or watchpoint, or until `limit` instructions were executed
+    pub fn continue_(&mut self, limit: Option<usize>) -> StopReason {
+        let mut count = 0;
+        loop {
+            if limit == Some(count) {
+                return StopReason::Limit;
+            }
+            let hits = self.step();
+            if !hits.is_empty() {
+                return StopReason::Watchpoint(hits);
+            }
+            count += 1;
+            let pc = self.pc();
+            let bus = &self.gb.cpu().bus;
+            if let Some(breakpoint) = self.breakpoints.iter().find(|b| b.matches(bus, pc)) {
+                return StopReason::Breakpoint(*breakpoint);
+            }
+        }
+    }
+
+    fn log_stack_change(&mut self, instruction: u64, change: StackChange) -> () {
+        let Some(oldest) = self.gb.rewind().and_then(|rewind| rewind.oldest()) else {
+            return;
+        };
+        let start = oldest.instructions;
+        while self
+            .stack_changes
+            .front()
+            .is_some_and(|(instruction, _)| *instruction < start)
+        {
+            self.stack_changes.pop_front();
+        }
+        self.stack_changes.push_back((instruction, change));
+    }
+
+    /// Undoes the last `count` instructions
+    pub fn step_back(&mut self, count: u64) -> anyhow::Result<()> {
+        let from = self.gb.instructions();
+        self.gb.step_back(count)?;
+        self.went_back(from);
+        return Ok(());
+    }
+
+    /// Goes back to the start of the frame `frames` before the current one
+    pub fn rewind_frames(&mut self, frames: u64) -> anyhow::Result<()> {
+        let from = self.gb.instructions();
+        self.gb.rewind_frames(frames)?;
+        self.went_back(from);
+        return Ok(());
+    }
+
+    /// Undoes the call stack changes and history of instructions no longer executed
+    fn went_back(&mut self, from: u64) -> () {
+        let now = self.gb.instructions();
+        while let Some(&(instruction, change)) = self.stack_changes.back() {
+            if instruction < now {
+                break;
+            }
+            self.stack_changes.pop_back();
+            match change {
+                StackChange::Call => {
+                    self.call_stack.pop();
+                }
+                StackChange::Return(frame) => self.call_stack.push(frame),
+            }
+        }
+        let undone = (from - now).min(self.history.len() as u64);
+        self.history.truncate(self.history.len() - undone as usize);
+    }
+
+    pub fn run_command(&mut self, command: &Command) -> anyhow::Result<String> {
+        let output = match command {
+            Command::Step(count) => {
+                let reason = self.step_n(*count);
+                self.describe_stop(&reason)
+            }
+            Command::Continue(limit) => {
+                let reason = self.continue_(*limit);
+                self.describe_stop(&reason)
+            }
+            Command::Back(count) => {
+                self.step_back(*count)?;
+                self.describe_stop(&StopReason::Step)
+            }
+            Command::Rewind(frames) => {
+                self.rewind_frames(*frames)?;
+                self.describe_stop(&StopReason::Step)
+            }
+            Command::Break(breakpoint) => {
+                self.breakpoints.insert(*breakpoint);
+                format!("Breakpoint at {}", self.describe_breakpoint(breakpoint))
+            }
+            Command::Delete(breakpoint) => {
+                if !self.breakpoints.remove(breakpoint) {
+                    anyhow::bail!("no breakpoint at {}", breakpoint);
+                }
+                format!(
+                    "Deleted breakpoint at {}",
+                    self.describe_breakpoint(breakpoint)
+                )
+            }
+            Command::Breakpoints => self
+                .breakpoints
+                .iter()
+                .map(|breakpoint| self.describe_breakpoint(breakpoint))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Command::Watch(watchpoint) => {
+                self.gb.add_watchpoint(*watchpoint);
+                format!(
+                    "Watchpoint {}: {}",
+                    self.gb.watchpoints().len() - 1,
+                    watchpoint
+                )
+            }
+            Command::Unwatch(i) => {
+                let watchpoint = match self.gb.watchpoints().get(*i) {
+                    Some(watchpoint) => *watchpoint,
+                    None => anyhow::bail!("no watchpoint {}", i),
+                };
+                self.gb.remove_watchpoint(&watchpoint);
+                format!("Deleted watchpoint {}: {}", i, watchpoint)
+            }
+            Command::Watchpoints => self
+                .gb
+                .watchpoints()
+                .iter()
+                .enumerate()
+                .map(|(i, watchpoint)| format!("{}: {}", i, watchpoint))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Command::Registers => self.registers(),
+            Command::Set(register, val) => {
+                self.set_register(register, *val)?;
+                self.registers()
+            }
+            Command::Dump(addr, len) => self.dump(*addr, *len),
+            Command::Disassemble(addr, count) => self.disassemble(*addr, *count),
+            Command::Backtrace => self.backtrace(),
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        };
+        return Ok(output);
+    }
+
+    pub fn describe_stop(&self, reason: &StopReason) -> String {
+        let next = self.format_instruction(&self.decode(self.pc()), "");
+        return match reason {
+            StopReason::Breakpoint(breakpoint) => format!(
+                "Breakpoint at {}\n{}",
+                self.describe_breakpoint(breakpoint),
+                next
+            ),
+            StopReason::Watchpoint(hits) => {
+                let hits: Vec<String> = hits.iter().map(|hit| hit.to_string()).collect();
+                format!("{}\n{}", hits.join("\n"), next)
+            }
+            StopReason::Step | StopReason::Limit => next,
+        };
+    }
+
+    /// ` <Label+$N>` for the closest label, or nothing without symbols
+    pub fn label_suffix(&self, bank: Option<usize>, addr: u16) -> String {
+        let location = self
+            .gb
+            .symbols()
+            .and_then(|symbols| symbols.locate_in(bank, addr));
+        return match location {
+            Some(location) => format!(" <{}>", location),
+            None => String::new(),
+        };
+    }
+
+    /// `$ADDR <Label+$N>`, in the bank currently mapped at `addr`
+    pub fn describe_addr(&self, addr: u16) -> String {
+        let bank = self.gb.cpu().bus.bank(addr);
+        return format!("${:04X}{}", addr, self.label_suffix(bank, addr));
+    }
+
+    fn describe_breakpoint(&self, breakpoint: &Breakpoint) -> String {
+        let bank = breakpoint
+            .bank
+            .or_else(|| self.gb.cpu().bus.bank(breakpoint.addr));
+        return format!("{}{}", breakpoint, self.label_suffix(bank, breakpoint.addr));
+    }
+
+    /// The instruction after `marker`, under its label and with the label of its operand
+    fn format_instruction(&self, instruction: &Instruction, marker: &str) -> String {
+        let bus = &self.gb.cpu().bus;
+        let label_at = |addr: u16| {
+            self.gb
+                .symbols()
+                .and_then(|symbols| symbols.label_at(bus, addr))
+        };
+
+        let mut line = format!("{}{}", marker, instruction);
+        if let Some(label) = instruction.operand_address().and_then(label_at) {
+            let _ = write!(line, "  ; {}", label);
+        }
+        return match label_at(instruction.address) {
+            Some(label) => format!("{}:\n{}", label, line),
+            None => line,
+        };
+    }
+
+    pub fn registers(&self) -> String {
+        let cpu = self.gb.cpu();
+        let r = &cpu.registers;
+        let flags = &cpu.flag_registers;
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        return format!(
+            "AF=${:02X}{:02X} BC=${:04X} DE=${:04X} HL=${:04X} SP=${:04X} PC=${:04X} flags={}{}{}{} IME={}",
+            r.a,
+            flags.bits(),
+            r.bc,
+            r.de,
+            r.hl,
+            r.sp,
+            r.pc,
+            flag(flags.z, 'Z'),
+            flag(flags.n, 'N'),
+            flag(flags.h, 'H'),
+            flag(flags.c, 'C'),
+            cpu.ime() as u8,
+        );
+    }
+
+    pub fn set_register(&mut self, register: &str, val: u16) -> anyhow::Result<()> {
+        let cpu = self.gb.cpu_mut();
+        let r = &mut cpu.registers;
+        let byte = val as u8;
+        let set_high = |pair: &mut u16| *pair = (*pair & 0x00FF) | (byte as u16) << 8;
+        let set_low = |pair: &mut u16| *pair = (*pair & 0xFF00) | byte as u16;
+        match register {
+            "a" => r.a = byte,
+            "b" => set_high(&mut r.bc),
+            "c" => set_low(&mut r.bc),
+            "d" => set_high(&mut r.de),
+            "e" => set_low(&mut r.de),
+            "h" => set_high(&mut r.hl),
+            "l" => set_low(&mut r.hl),
+            "bc" => r.bc = val,
+            "de" => r.de = val,
+            "hl" => r.hl = val,
+            "sp" => r.sp = val,
+            "pc" => r.pc = val,
+            "f" | "af" => {
+                if register == "af" {
+                    r.a = (val >> 8) as u8;
+                }
+                cpu.set_flags(byte);
+            }
+            "z" => cpu.flag_registers.z = val != 0,
+            "n" => cpu.flag_registers.n = val != 0,
+            // "h" and "c" are registers
+            "hf" => cpu.flag_registers.h = val != 0,
+            "cy" => cpu.flag_registers.c = val != 0,
+            _ => anyhow::bail!("unknown register: {}", register),
+        }
+        self.edited();
+        return Ok(());
+    }
+
+    /// Writes memory as the CPU would, without reporting watchpoint hits
+    pub fn write_memory(&mut self, addr: u16, bytes: &[u8]) -> () {
+        let bus = &mut self.gb.cpu_mut().bus;
+        for (i, val) in bytes.iter().enumerate() {
+            bus.write_byte(addr.wrapping_add(i as u16), *val);
+        }
+        self.gb.take_watch_hits();
+        self.edited();
+    }
+
+    /// Going back would replay without changes made from the debugger,
+    /// so the history starts again from the edited state
+    pub fn edited(&mut self) -> () {
+        self.gb.restart_rewind();
+        self.stack_changes.clear();
+    }
+
+    /// 16 bytes per line with their ASCII
+    pub fn dump(&self, addr: u16, len: u16) -> String {
+        let bus = &self.gb.cpu().bus;
+        let mut output = String::new();
+        let mut line_addr = addr;
+        let end = addr as u32 + len as u32;
+        while (line_addr as u32) < end {
+            let count = (end - line_addr as u32).min(16) as u16;
+            let bytes: Vec<u8> = (0..count)
+                .map(|i| bus.peek(line_addr.wrapping_add(i)))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            let _ = writeln!(
+                output,
+                "${:04X}: {:<47}  {}",
+                line_addr,
+                hex.join(" "),
+                ascii
+            );
+            if line_addr.checked_add(16).is_none() {
+                break;
+            }
+            line_addr += 16;
+        }
+        return output.trim_end().to_string();
+    }
+
+    /// From `addr`, or from the last executed instructions up to PC and beyond
+    pub fn disassemble(&self, addr: Option<u16>, count: usize) -> String {
+        let pc = self.pc();
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut next = match addr {
+            Some(addr) => addr,
+            None => {
+                instructions.extend(
+                    self.history
+                        .iter()
+                        .filter(|addr| **addr != pc)
+                        .map(|addr| self.decode(*addr)),
+                );
+                pc
+            }
+        };
+        for _ in 0..count {
+            let instruction = self.decode(next);
+            next = next.wrapping_add(instruction.length());
+            instructions.push(instruction);
+        }
+
+        return instructions
+            .iter()
+            .map(|i| {
+                let marker = if i.address == pc { "=> " } else { "   " };
+                self.format_instruction(i, marker)
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+    }
+
+    pub fn backtrace(&self) -> String {
+        let mut lines = vec![format!("#0 {}", self.describe_addr(self.pc()))];
+        for (i, frame) in self.call_stack.iter().rev().enumerate() {
+            lines.push(format!(
+                "#{} ${:04X}{} called ${:04X}{}, returns to ${:04X}",
+                i + 1,
+                frame.call_site,
+                self.label_suffix(frame.bank, frame.call_site),
+                frame.target,
+                self.label_suffix(frame.target_bank, frame.target),
+                frame.return_addr
+            ));
+        }
+        return lines.join("\n");
+    }
+}
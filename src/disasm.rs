@@ -0,0 +1,304 @@
+use crate::bus::Bus;
+use crate::cpu::{prefixed_cycles, OPCODE_CYCLES};
+use crate::rom::Rom;
+
+use std::fmt;
+
+const R: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const RP: [&str; 4] = ["bc", "de", "hl", "sp"];
+const RP2: [&str; 4] = ["bc", "de", "hl", "af"];
+const CC: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = [
+    "add a,", "adc a,", "sub", "sbc a,", "and", "xor", "or", "cp",
+];
+const ROT: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACC: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+/// A decoded instruction in RGBDS syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    // Machine cycles, for conditional branches when not taken
+    pub cycles: u8,
+    // Machine cycles of a conditional branch that is taken
+    pub branch_cycles: Option<u8>,
+}
+
+impl Instruction {
+    pub fn length(&self) -> u16 {
+        return self.bytes.len() as u16;
+    }
+
+    /// Address named by the operand: a jump, call or RST target, or a memory operand
+    pub fn operand_address(&self) -> Option<u16> {
+        let opcode = self.bytes[0];
+        let nn = || u16::from_le_bytes([self.bytes[1], self.bytes[2]]);
+        return match opcode {
+            0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(
+                self.address
+                    .wrapping_add(2)
+                    .wrapping_add(self.bytes[1] as i8 as u16),
+            ),
+            0x01 | 0x08 | 0x11 | 0x21 | 0x31 => Some(nn()),
+            0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2 | 0xD4 | 0xDA | 0xDC => Some(nn()),
+            0xEA | 0xFA => Some(nn()),
+            0xE0 | 0xF0 => Some(0xFF00 | self.bytes[1] as u16),
+            _ if opcode & 0xC7 == 0xC7 => Some((opcode & 0x38) as u16),
+            _ => None,
+        };
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        return write!(f, "${:04X}: {:<8}  {}", self.address, bytes, self.mnemonic);
+    }
+}
+
+/// Decodes the instruction at `addr` from the CPU's view of memory
+pub fn decode_bus(bus: &Bus, addr: u16) -> Instruction {
+    return decode(|a| bus.peek(a), addr);
+}
+
+/// Decodes the instruction at a ROM file offset without running anything.
+/// Offsets past the first bank are shown at their address in the $4000-$7FFF window.
+pub fn decode_rom(rom: &Rom, offset: usize) -> Instruction {
+    let addr = if offset < 0x4000 {
+        offset as u16
+    } else {
+        0x4000 + (offset % 0x4000) as u16
+    };
+    return decode(
+        |a| {
+            let i = offset + a.wrapping_sub(addr) as usize;
+            return rom.value.get(i).copied().unwrap_or(0xFF);
+        },
+        addr,
+    );
+}
+
+/// Decodes consecutive instructions starting at `addr`
+pub fn disassemble(read: impl Fn(u16) -> u8, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut addr = addr;
+    for _ in 0..count {
+        let instruction = decode(&read, addr);
+        addr = addr.wrapping_add(instruction.length());
+        instructions.push(instruction);
+    }
+    return instructions;
+}
+
+/// https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+pub fn decode(read: impl Fn(u16) -> u8, addr: u16) -> Instruction {
+    let opcode = read(addr);
+    let n = read(addr.wrapping_add(1));
+    let nn = u16::from_le_bytes([n, read(addr.wrapping_add(2))]);
+    // Relative jumps are shown with their target
+    let target = addr.wrapping_add(2).wrapping_add(n as i8 as u16);
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    let p = (y >> 1) as usize;
+    let q = y & 0x01;
+    let (y, z) = (y as usize, z as usize);
+
+    let mut cycles = OPCODE_CYCLES[opcode as usize];
+    let mut branch_cycles = None;
+
+    // Text and length of the instruction
+    let (mnemonic, length) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("nop".to_string(), 1),
+            1 => (format!("ld [${:04X}], sp", nn), 3),
+            2 => ("stop".to_string(), 2),
+            3 => (format!("jr ${:04X}", target), 2),
+            _ => {
+                branch_cycles = Some(cycles + 1);
+                (format!("jr {}, ${:04X}", CC[y - 4], target), 2)
+            }
+        },
+        (0, 1) if q == 0 => (format!("ld {}, ${:04X}", RP[p], nn), 3),
+        (0, 1) => (format!("add hl, {}", RP[p]), 1),
+        (0, 2) => {
+            let pointer = ["[bc]", "[de]", "[hl+]", "[hl-]"][p];
+            if q == 0 {
+                (format!("ld {}, a", pointer), 1)
+            } else {
+                (format!("ld a, {}", pointer), 1)
+            }
+        }
+        (0, 3) if q == 0 => (format!("inc {}", RP[p]), 1),
+        (0, 3) => (format!("dec {}", RP[p]), 1),
+        (0, 4) => (format!("inc {}", R[y]), 1),
+        (0, 5) => (format!("dec {}", R[y]), 1),
+        (0, 6) => (format!("ld {}, ${:02X}", R[y], n), 2),
+        (0, _) => (ACC[y].to_string(), 1),
+
+        (1, 6) if y == 6 => ("halt".to_string(), 1),
+        (1, _) => (format!("ld {}, {}", R[y], R[z]), 1),
+
+        (2, _) => (format!("{} {}", ALU[y], R[z]), 1),
+
+        (3, 0) => match y {
+            0..=3 => {
+                branch_cycles = Some(cycles + 3);
+                (format!("ret {}", CC[y]), 1)
+            }
+            4 => (format!("ldh [$FF{:02X}], a", n), 2),
+            5 => (format!("add sp, {}", n as i8), 2),
+            6 => (format!("ldh a, [$FF{:02X}]", n), 2),
+            _ => (format!("ld hl, sp{:+}", n as i8), 2),
+        },
+        (3, 1) if q == 0 => (format!("pop {}", RP2[p]), 1),
+        (3, 1) => (["ret", "reti", "jp hl", "ld sp, hl"][p].to_string(), 1),
+        (3, 2) => match y {
+            0..=3 => {
+                branch_cycles = Some(cycles + 1);
+                (format!("jp {}, ${:04X}", CC[y], nn), 3)
+            }
+            4 => ("ldh [c], a".to_string(), 1),
+            5 => (format!("ld [${:04X}], a", nn), 3),
+            6 => ("ldh a, [c]".to_string(), 1),
+            _ => (format!("ld a, [${:04X}]", nn), 3),
+        },
+        (3, 3) => match y {
+            0 => (format!("jp ${:04X}", nn), 3),
+            1 => {
+                cycles = prefixed_cycles(n);
+                (decode_prefixed(n), 2)
+            }
+            6 => ("di".to_string(), 1),
+            7 => ("ei".to_string(), 1),
+            _ => (format!("db ${:02X}", opcode), 1),
+        },
+        (3, 4) if y < 4 => {
+            branch_cycles = Some(cycles + 3);
+            (format!("call {}, ${:04X}", CC[y], nn), 3)
+        }
+        (3, 5) if q == 0 => (format!("push {}", RP2[p]), 1),
+        (3, 5) if p == 0 => (format!("call ${:04X}", nn), 3),
+        (3, 6) => (format!("{} ${:02X}", ALU[y], n), 2),
+        (3, 7) => (format!("rst ${:02X}", y * 8), 1),
+        // $D3, $DB, $DD, $E3, $E4, $EB-$ED, $F4, $FC, $FD lock up the CPU
+        _ => (format!("db ${:02X}", opcode), 1),
+    };
+
+    let bytes = (0..length).map(|i| read(addr.wrapping_add(i))).collect();
+    return Instruction {
+        address: addr,
+        bytes,
+        mnemonic,
+        cycles,
+        branch_cycles,
+    };
+}
+
+fn decode_prefixed(opcode: u8) -> String {
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let r = R[(opcode & 0x07) as usize];
+    return match opcode >> 6 {
+        0 => format!("{} {}", ROT[y], r),
+        1 => format!("bit {}, {}", y, r),
+        2 => format!("res {}, {}", y, r),
+        _ => format!("set {}, {}", y, r),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bytes, text, length, cycles, cycles when taken
+    type Case = (&'static [u8], &'static str, u16, u8, Option<u8>);
+
+    fn decode_bytes(bytes: &[u8]) -> Instruction {
+        return decode(
+            |a| bytes.get(a as usize - 0x100).copied().unwrap_or(0),
+            0x100,
+        );
+    }
+
+    #[test]
+    fn one_opcode_per_group() {
+        #[rustfmt::skip]
+        let cases: &[Case] = &[
+            // x = 0
+            (&[0x00], "nop", 1, 1, None),
+            (&[0x08, 0x34, 0x12], "ld [$1234], sp", 3, 5, None),
+            (&[0x10, 0x00], "stop", 2, 1, None),
+            (&[0x18, 0xFE], "jr $0100", 2, 3, None),
+            (&[0x20, 0x05], "jr nz, $0107", 2, 2, Some(3)),
+            (&[0x01, 0x34, 0x12], "ld bc, $1234", 3, 3, None),
+            (&[0x39], "add hl, sp", 1, 2, None),
+            (&[0x22], "ld [hl+], a", 1, 2, None),
+            (&[0x1A], "ld a, [de]", 1, 2, None),
+            (&[0x03], "inc bc", 1, 2, None),
+            (&[0x3B], "dec sp", 1, 2, None),
+            (&[0x34], "inc [hl]", 1, 3, None),
+            (&[0x0D], "dec c", 1, 1, None),
+            (&[0x36, 0x42], "ld [hl], $42", 2, 3, None),
+            (&[0x27], "daa", 1, 1, None),
+            // x = 1
+            (&[0x76], "halt", 1, 1, None),
+            (&[0x78], "ld a, b", 1, 1, None),
+            (&[0x46], "ld b, [hl]", 1, 2, None),
+            // x = 2
+            (&[0x86], "add a, [hl]", 1, 2, None),
+            (&[0xA9], "xor c", 1, 1, None),
+            // x = 3
+            (&[0xC8], "ret z", 1, 2, Some(5)),
+            (&[0xE0, 0x44], "ldh [$FF44], a", 2, 3, None),
+            (&[0xE8, 0xFE], "add sp, -2", 2, 4, None),
+            (&[0xF8, 0x05], "ld hl, sp+5", 2, 3, None),
+            (&[0xC1], "pop bc", 1, 3, None),
+            (&[0xD9], "reti", 1, 4, None),
+            (&[0xE9], "jp hl", 1, 1, None),
+            (&[0xDA, 0x34, 0x12], "jp c, $1234", 3, 3, Some(4)),
+            (&[0xF2], "ldh a, [c]", 1, 2, None),
+            (&[0xFA, 0x34, 0x12], "ld a, [$1234]", 3, 4, None),
+            (&[0xC3, 0x34, 0x12], "jp $1234", 3, 4, None),
+            (&[0xF3], "di", 1, 1, None),
+            (&[0xD3], "db $D3", 1, 0, None),
+            (&[0xC4, 0x34, 0x12], "call nz, $1234", 3, 3, Some(6)),
+            (&[0xF5], "push af", 1, 4, None),
+            (&[0xCD, 0x34, 0x12], "call $1234", 3, 6, None),
+            (&[0xFE, 0x90], "cp $90", 2, 2, None),
+            (&[0xFF], "rst $38", 1, 4, None),
+            // CB prefix
+            (&[0xCB, 0x37], "swap a", 2, 2, None),
+            (&[0xCB, 0x7E], "bit 7, [hl]", 2, 3, None),
+            (&[0xCB, 0x86], "res 0, [hl]", 2, 4, None),
+            (&[0xCB, 0xFF], "set 7, a", 2, 2, None),
+        ];
+
+        for &(bytes, mnemonic, length, cycles, branch_cycles) in cases {
+            let instruction = decode_bytes(bytes);
+            assert_eq!(instruction.mnemonic, mnemonic, "{:02X?}", bytes);
+            assert_eq!(instruction.length(), length, "{}", mnemonic);
+            assert_eq!(instruction.bytes, bytes, "{}", mnemonic);
+            assert_eq!(instruction.cycles, cycles, "{}", mnemonic);
+            assert_eq!(instruction.branch_cycles, branch_cycles, "{}", mnemonic);
+        }
+    }
+
+    #[test]
+    fn disassemble_follows_lengths() {
+        let code = [0x3E, 0x01, 0xCD, 0x00, 0x02, 0xC9];
+        let read = |a: u16| code.get(a as usize - 0x100).copied().unwrap_or(0);
+        let addresses: Vec<u16> = disassemble(read, 0x100, 3)
+            .iter()
+            .map(|instruction| instruction.address)
+            .collect();
+        assert_eq!(addresses, vec![0x100, 0x102, 0x105]);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::ppu::OAM_SIZE;
+
+/// OAM DMA transfer
+/// <https://gbdev.io/pandocs/OAM_DMA_Transfer.html>
+///
+/// Writing to $FF46 copies $XX00-$XX9F to $FE00-$FE9F, one byte per M-cycle.
+/// While the transfer runs the CPU can only access HRAM (and the IO registers).
+#[derive(Clone)]
+pub struct Dma {
+    // value written to $FF46
+    pub source: u8,
+    pub active: bool,
+    // number of bytes already transferred
+    pub index: u16,
+    // byte currently on the bus, returned to conflicting CPU reads
+    pub value: u8,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        return Dma {
+            source: 0xFF,
+            active: false,
+            index: 0,
+            value: 0xFF,
+        };
+    }
+
+    pub fn start(&mut self, val: u8) {
+        self.source = val;
+        self.active = true;
+        self.index = 0;
+    }
+
+    /// Address of the next byte to copy.
+    /// Sources above $DF00 hit the echo of work RAM on DMG.
+    pub fn current_addr(&self) -> u16 {
+        let high = if self.source >= 0xE0 {
+            self.source - 0x20
+        } else {
+            self.source
+        };
+        return ((high as u16) << 8) | self.index;
+    }
+
+    /// Records the transferred byte and returns the OAM offset it belongs to
+    pub fn advance(&mut self, val: u8) -> usize {
+        let offset = self.index as usize;
+        self.value = val;
+        self.index += 1;
+        if self.index as usize >= OAM_SIZE {
+            self.active = false;
+        }
+        return offset;
+    }
+
+    /// The CPU keeps access to HRAM and the IO registers during a transfer
+    pub fn blocks(&self, addr: u16) -> bool {
+        return self.active && addr < 0xFF00;
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Dma {
+        return Dma::new();
+    }
+}
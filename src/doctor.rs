@@ -0,0 +1,101 @@
+use crate::disasm::{decode_bus, Instruction};
+use crate::gb::Gb;
+use crate::trace::trace_line;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::BufRead;
+
+// Gameboy Doctor logs are made with LY stuck at $90
+pub const DOCTOR_LY: u8 = 0x90;
+
+/// Result of comparing our trace with a reference log
+pub enum Comparison {
+    // every line of the reference matched
+    Match { lines: usize },
+    Divergence(Divergence),
+}
+
+pub struct Divergence {
+    // 1-based line of the reference log
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    // matching lines before the divergence
+    pub context: Vec<String>,
+    // the instruction that produced the wrong state, None on the first line
+    pub previous: Option<Instruction>,
+    pub next: Instruction,
+}
+
+impl Divergence {
+    /// Names of the fields that differ, e.g. ["A", "F"]
+    pub fn fields(&self) -> Vec<String> {
+        return self
+            .expected
+            .split_whitespace()
+            .zip(self.actual.split_whitespace())
+            .filter(|(expected, actual)| expected != actual)
+            .map(|(expected, _)| expected.split(':').next().unwrap_or("").to_string())
+            .collect();
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Divergence at line {}", self.line)?;
+        for line in &self.context {
+            writeln!(f, "           {}", line)?;
+        }
+        writeln!(f, "expected:  {}", self.expected)?;
+        writeln!(f, "actual:    {}", self.actual)?;
+        writeln!(f, "fields:    {}", self.fields().join(", "))?;
+        if let Some(previous) = &self.previous {
+            writeln!(f, "executed:  {}", previous)?;
+        }
+        return write!(f, "next:      {}", self.next);
+    }
+}
+
+/// Runs the emulator one instruction per reference line until a line differs.
+/// `context` is the number of matching lines kept for the report.
+pub fn compare(gb: &mut Gb, reference: impl BufRead, context: usize) -> anyhow::Result<Comparison> {
+    gb.cpu_mut().bus.ly_override = Some(DOCTOR_LY);
+
+    let mut history: VecDeque<String> = VecDeque::with_capacity(context + 1);
+    let mut previous = None;
+    let mut lines = 0;
+    for (i, expected) in reference.lines().enumerate() {
+        let expected = expected?;
+        // labels of our own traces follow a `;`
+        let expected = expected.split(';').next().unwrap_or("").trim();
+        if expected.is_empty() {
+            continue;
+        }
+
+        // interrupt dispatch and HALT are not lines of the trace
+        while gb.cpu_mut().interrupt_or_idle() {}
+        let actual = trace_line(gb.cpu());
+        let next = decode_bus(&gb.cpu().bus, gb.cpu().registers.pc);
+        if actual != expected {
+            return Ok(Comparison::Divergence(Divergence {
+                line: i + 1,
+                expected: expected.to_string(),
+                actual,
+                context: history.into(),
+                previous,
+                next,
+            }));
+        }
+
+        history.push_back(actual);
+        if history.len() > context {
+            history.pop_front();
+        }
+        previous = Some(next);
+        lines += 1;
+        while !gb.step() {}
+    }
+
+    return Ok(Comparison::Match { lines });
+}
@@ -1,30 +1,514 @@
+use crate::apu::{Apu, Channel};
 use crate::bus::Bus;
+use crate::compat_palette::CompatPalettes;
+use crate::coverage::Coverage;
 use crate::cpu::Cpu;
+use crate::joypad::{Button, MAX_PLAYERS};
 use crate::mbc::new_mbc;
+use crate::model::Model;
+use crate::palette::{rgb555_to_rgba, Palette};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::profiler::Profiler;
+use crate::recorder::Recorder;
+use crate::rewind::{Input, Rewind, SaveState};
 use crate::rom::Rom;
+use crate::screenshot::save_png;
+use crate::sgb::{Sgb, BORDER_HEIGHT, BORDER_WIDTH};
+use crate::symbols::Symbols;
+use crate::trace::Trace;
+use crate::watchpoint::{WatchHit, Watchpoint};
+use crate::wav::{save_wav, save_wav_mono};
 
 use std::fs::File;
 use std::io::BufReader;
 
 pub struct Gb {
     cpu: Cpu,
+    // APU output collected while audio capture is on
+    audio: Option<Vec<(f32, f32)>>,
+    // receives every completed frame while recording
+    recorder: Option<Recorder>,
+    last_frame: u64,
+    // colors of the DMG shades in screenshots and recordings,
+    // unused when CGB hardware colorizes a DMG game
+    palette: Palette,
+    // labels for traces and the debugger
+    symbols: Option<Symbols>,
+    // instructions executed so far, without interrupt dispatches and HALT cycles
+    instructions: u64,
+    // CPU steps so far, which is where inputs are logged for rewinding
+    steps: u64,
+    rewind: Option<Rewind>,
 }
 
 impl Gb {
-    #[allow(unused_variables)]
+    /// Picks CGB for games with CGB support and DMG otherwise
     pub fn new(rom_path: &str) -> Gb {
+        return Gb::build(rom_path, None);
+    }
+
+    pub fn with_model(rom_path: &str, model: Model) -> Gb {
+        return Gb::build(rom_path, Some(model));
+    }
+
+    fn build(rom_path: &str, model: Option<Model>) -> Gb {
         let mut reader = BufReader::new(File::open(rom_path).unwrap());
-        let rom = Rom::new(&mut reader);
+        return Gb::from_rom(Rom::new(&mut reader), model);
+    }
+
+    /// Like `new` and `with_model` for a ROM that is already loaded
+    pub fn from_rom(rom: Rom, model: Option<Model>) -> Gb {
+        let model = model.unwrap_or_else(|| Model::for_rom(&rom));
+        let cgb_game = rom.cgb_flag;
+        // The SGB also requires the old licensee code to be $33
+        let sgb_game = rom.sgb_flag && rom.old_licensee_code == 0x33;
+        let boot = model.boot_registers(&rom);
+        // Picked by the CGB boot ROM, which is not run
+        let compat_palettes = if model.is_cgb() && !cgb_game {
+            Some(CompatPalettes::for_rom(&rom))
+        } else {
+            None
+        };
 
         let mbc = new_mbc(rom);
-        let bus = Bus::new(mbc);
+        let mut bus = Bus::new(mbc);
+        bus.set_model(model, cgb_game);
+        if let Some(palettes) = &compat_palettes {
+            bus.ppu.set_compat_palettes(palettes);
+        }
+        if model.is_sgb() && sgb_game {
+            bus.sgb = Some(Sgb::new());
+        }
+
+        let mut cpu = Cpu::new(bus);
+        cpu.set_boot_registers(boot);
+
+        return Gb {
+            cpu,
+            audio: None,
+            recorder: None,
+            last_frame: 0,
+            palette: Palette::default(),
+            symbols: None,
+            instructions: 0,
+            steps: 0,
+            rewind: None,
+        };
+    }
+
+    /// Returns whether an instruction was executed, see `Cpu::step`
+    pub fn step(&mut self) -> bool {
+        let executed = self.cpu.step();
+        self.steps += 1;
+        if executed {
+            self.instructions += 1;
+        }
+
+        let samples = &mut self.cpu.bus.apu.samples;
+        match self.audio.as_mut() {
+            Some(audio) => audio.append(samples),
+            None => samples.clear(),
+        }
+
+        if self.frame() != self.last_frame {
+            self.last_frame = self.frame();
+            if self.recorder.is_some() {
+                let rgba = self.screenshot();
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.push_frame(&rgba);
+                }
+            }
+            if let Some(mut rewind) = self.rewind.take() {
+                if rewind.due(self.frame()) {
+                    rewind.push(self.save_state());
+                }
+                self.rewind = Some(rewind);
+            }
+        }
+        return executed;
+    }
+
+    pub fn model(&self) -> Model {
+        return self.cpu.bus.model;
+    }
+
+    /// Runs until the PPU completes the current frame
+    pub fn run_frame(&mut self) -> () {
+        let frame = self.frame();
+        while self.frame() == frame {
+            self.step();
+        }
+    }
+
+    /// Runs like `run_frame`, stopping after an instruction that hits a watchpoint
+    pub fn run_frame_watched(&mut self) -> Vec<WatchHit> {
+        let frame = self.frame();
+        while self.frame() == frame {
+            self.step();
+            let hits = self.take_watch_hits();
+            if !hits.is_empty() {
+                return hits;
+            }
+        }
+        return Vec::new();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> () {
+        self.cpu.bus.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let watchpoints = &mut self.cpu.bus.watchpoints;
+        let len = watchpoints.len();
+        watchpoints.retain(|w| w != watchpoint);
+        return watchpoints.len() != len;
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        return &self.cpu.bus.watchpoints;
+    }
+
+    /// Accesses that matched a watchpoint since the last call
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        return self.cpu.bus.take_watch_hits();
+    }
+
+    /// Number of frames completed so far
+    pub fn frame(&self) -> u64 {
+        return self.cpu.bus.ppu.frame;
+    }
+
+    /// RGBA pixels of the current frame, 160x144
+    pub fn screenshot(&self) -> Vec<u8> {
+        let ppu = &self.cpu.bus.ppu;
+        if let Some(sgb) = &self.cpu.bus.sgb {
+            return rgb555_to_rgba(&sgb.screen);
+        }
+        if ppu.cgb || ppu.dmg_compat {
+            return rgb555_to_rgba(&ppu.cgb_framebuffer);
+        }
+        return self.palette.to_rgba(&ppu.framebuffer);
+    }
+
+    pub fn palette(&self) -> Palette {
+        return self.palette;
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) -> () {
+        self.palette = palette;
+    }
+
+    pub fn save_screenshot(&self, path: &str) -> anyhow::Result<()> {
+        return save_png(
+            path,
+            SCREEN_WIDTH as u32,
+            SCREEN_HEIGHT as u32,
+            &self.screenshot(),
+        );
+    }
+
+    /// RGBA pixels of the SGB output with the border, 256x224
+    pub fn sgb_screenshot(&self) -> Option<Vec<u8>> {
+        return self
+            .cpu
+            .bus
+            .sgb
+            .as_ref()
+            .map(|sgb| rgb555_to_rgba(&sgb.framebuffer));
+    }
+
+    pub fn save_sgb_screenshot(&self, path: &str) -> anyhow::Result<()> {
+        return match self.sgb_screenshot() {
+            Some(rgba) => save_png(path, BORDER_WIDTH as u32, BORDER_HEIGHT as u32, &rgba),
+            None => Err(anyhow::anyhow!(
+                "SGB functions are not enabled for this game"
+            )),
+        };
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> () {
+        self.press(0, button, pressed);
+    }
+
+    /// Joypads 2-4 (players 1-3) are read by games that enabled the SGB multiplayer mode
+    pub fn set_player_button(
+        &mut self,
+        player: usize,
+        button: Button,
+        pressed: bool,
+    ) -> anyhow::Result<()> {
+        if player >= MAX_PLAYERS {
+            anyhow::bail!(
+                "no joypad for player {}, players are 0-{}",
+                player,
+                MAX_PLAYERS - 1
+            );
+        }
+        self.press(player, button, pressed);
+        return Ok(());
+    }
+
+    fn press(&mut self, player: usize, button: Button, pressed: bool) -> () {
+        self.cpu.bus.joypad.set_pressed(player, button, pressed);
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.log_input(Input {
+                step: self.steps,
+                player,
+                button,
+                pressed,
+            });
+        }
+    }
+
+    /// Number of instructions executed so far
+    pub fn instructions(&self) -> u64 {
+        return self.instructions;
+    }
+
+    /// Copy of the emulator state to go back to with `load_state`
+    pub fn save_state(&mut self) -> SaveState {
+        return SaveState {
+            instructions: self.instructions,
+            steps: self.steps,
+            frame: self.frame(),
+            cpu: self.cpu.snapshot(),
+        };
+    }
+
+    /// Rewind history restarts from the loaded state
+    pub fn load_state(&mut self, state: &SaveState) -> () {
+        self.restore(state);
+        self.restart_rewind();
+    }
+
+    /// Drops the rewind history and starts it again from the current state.
+    /// Called after changes made through `cpu_mut`, which replays would not repeat
+    pub fn restart_rewind(&mut self) -> () {
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.clear();
+            rewind.push(self.save_state());
+            self.rewind = Some(rewind);
+        }
+    }
+
+    fn restore(&mut self, state: &SaveState) -> () {
+        self.cpu.restore(&state.cpu);
+        self.instructions = state.instructions;
+        self.steps = state.steps;
+        self.last_frame = self.frame();
+        self.cpu.bus.apu.samples.clear();
+    }
 
-        let cpu = Cpu::new(bus);
+    /// Keeps `capacity` snapshots, one every `interval` frames, and logs inputs
+    /// so that `rewind_frames` and `step_back` can go back as far as the oldest one
+    pub fn enable_rewind(&mut self, capacity: usize, interval: u64) -> () {
+        let mut rewind = Rewind::new(capacity, interval);
+        rewind.push(self.save_state());
+        self.rewind = Some(rewind);
+    }
+
+    pub fn disable_rewind(&mut self) -> () {
+        self.rewind = None;
+    }
+
+    pub fn rewind(&self) -> Option<&Rewind> {
+        return self.rewind.as_ref();
+    }
+
+    /// Goes back to the start of the frame `frames` before the current one
+    pub fn rewind_frames(&mut self, frames: u64) -> anyhow::Result<()> {
+        let target = self.frame().saturating_sub(frames);
+        return self.rewind_to(|state| state.frame <= target, |gb| gb.frame() >= target);
+    }
+
+    /// Undoes the last `count` instructions
+    pub fn step_back(&mut self, count: u64) -> anyhow::Result<()> {
+        let target = self.instructions.saturating_sub(count);
+        return self.rewind_to(
+            |state| state.instructions <= target,
+            |gb| gb.instructions >= target,
+        );
+    }
+
+    /// Loads the latest snapshot that `start` accepts and replays the logged inputs
+    /// until `reached`. Everything after that point is forgotten
+    fn rewind_to(
+        &mut self,
+        start: impl Fn(&SaveState) -> bool,
+        reached: impl Fn(&Gb) -> bool,
+    ) -> anyhow::Result<()> {
+        let Some(mut rewind) = self.rewind.take() else {
+            anyhow::bail!("rewinding is not enabled");
+        };
+        let Some(state) = rewind.latest(start) else {
+            let oldest = rewind
+                .oldest()
+                .map_or((self.frame(), self.instructions), |state| {
+                    (state.frame, state.instructions)
+                });
+            self.rewind = Some(rewind);
+            anyhow::bail!(
+                "history starts at frame {}, instruction {}",
+                oldest.0,
+                oldest.1
+            );
+        };
+        self.restore(state);
+
+        // the replayed instructions already went to the trace and profiler
+        let trace = self.cpu.trace.take();
+        let profiler = self.cpu.profiler.take();
+        let mut inputs = rewind.inputs_from(state.steps).iter().peekable();
+        while !reached(self) {
+            while let Some(input) = inputs.next_if(|input| input.step == self.steps) {
+                let joypad = &mut self.cpu.bus.joypad;
+                joypad.set_pressed(input.player, input.button, input.pressed);
+            }
+            if self.cpu.step() {
+                self.instructions += 1;
+            }
+            self.cpu.bus.apu.samples.clear();
+            self.steps += 1;
+        }
+        self.cpu.trace = trace;
+        self.cpu.profiler = profiler;
+        self.last_frame = self.frame();
+        self.take_watch_hits();
+
+        rewind.truncate(self.steps);
+        self.rewind = Some(rewind);
+        return Ok(());
+    }
+
+    /// Loads an RGBDS or no$gmb `.sym` file
+    pub fn load_symbols(&mut self, path: &str) -> anyhow::Result<()> {
+        self.set_symbols(Symbols::load(path)?);
+        return Ok(());
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) -> () {
+        if let Some(trace) = self.cpu.trace.as_mut() {
+            trace.symbols = Some(symbols.clone());
+        }
+        self.symbols = Some(symbols);
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        return self.symbols.as_ref();
+    }
+
+    /// Writes a Gameboy Doctor line before every instruction, "-" for stdout
+    pub fn start_trace(&mut self, path: &str) -> anyhow::Result<()> {
+        self.set_trace(Trace::from_path(path)?)?;
+        return Ok(());
+    }
+
+    /// Labels the trace with the loaded symbols unless it has its own
+    pub fn set_trace(&mut self, mut trace: Trace) -> anyhow::Result<()> {
+        self.stop_trace()?;
+        if trace.symbols.is_none() {
+            trace.symbols = self.symbols.clone();
+        }
+        self.cpu.trace = Some(trace);
+        return Ok(());
+    }
+
+    pub fn stop_trace(&mut self) -> anyhow::Result<()> {
+        return match self.cpu.trace.take() {
+            Some(trace) => trace.finish(),
+            None => Ok(()),
+        };
+    }
+
+    /// Records how ROM bytes are used, adding to `coverage` when given
+    pub fn start_coverage(&mut self, coverage: Option<Coverage>) -> () {
+        let coverage = coverage.unwrap_or_else(|| Coverage::new(self.cpu.bus.rom_len()));
+        self.cpu.bus.start_coverage(coverage);
+    }
+
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        return self.cpu.bus.take_coverage();
+    }
+
+    pub fn rom_len(&self) -> usize {
+        return self.cpu.bus.rom_len();
+    }
+
+    pub fn start_profiling(&mut self) -> () {
+        self.cpu.profiler = Some(Profiler::new());
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profiler> {
+        return self.cpu.profiler.take();
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        return self.cpu.profiler.as_ref();
+    }
+
+    /// Records every completed frame to a .gif or .y4m file,
+    /// keeping one frame out of `frame_skip + 1`
+    pub fn start_recording(&mut self, path: &str, frame_skip: u32) -> anyhow::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::from_path(path, frame_skip)?);
+        return Ok(());
+    }
+
+    pub fn stop_recording(&mut self) -> anyhow::Result<()> {
+        return match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        };
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.cpu.bus.apu.sample_rate;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> () {
+        self.cpu.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn start_audio_capture(&mut self) -> () {
+        self.audio = Some(Vec::new());
+    }
+
+    /// Stops capturing and returns the stereo samples collected so far
+    pub fn stop_audio_capture(&mut self) -> Vec<(f32, f32)> {
+        return self.audio.take().unwrap_or_default();
+    }
+
+    pub fn audio(&self) -> &[(f32, f32)] {
+        return self.audio.as_deref().unwrap_or(&[]);
+    }
+
+    /// Writes the captured audio as a 16-bit stereo WAV file
+    pub fn save_audio_wav(&self, path: &str) -> anyhow::Result<()> {
+        return save_wav(path, self.sample_rate(), self.audio());
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        return &self.cpu;
+    }
+
+    /// Registers and memory, for debugging tools
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        return &mut self.cpu;
+    }
 
-        return Gb { cpu };
+    /// Mute/solo and per-channel capture live on the APU
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        return &mut self.cpu.bus.apu;
     }
 
-    pub fn step(&mut self) -> () {
-        self.cpu.step();
+    /// Writes each captured channel to `{prefix}_ch{n}.wav` and stops the capture
+    pub fn save_channel_wavs(&mut self, prefix: &str) -> anyhow::Result<()> {
+        let sample_rate = self.sample_rate();
+        let channel_samples = self.cpu.bus.apu.stop_channel_capture();
+        for channel in Channel::ALL {
+            let path = format!("{}_ch{}.wav", prefix, channel as usize + 1);
+            save_wav_mono(&path, sample_rate, &channel_samples[channel as usize])?;
+        }
+        return Ok(());
     }
 }
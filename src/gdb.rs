@@ -0,0 +1,414 @@
+use crate::debugger::{Breakpoint, Debugger, StopReason};
+use crate::watchpoint::{WatchKind, Watchpoint};
+
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Instructions run between checks for an interrupt from the client
+const INTERRUPT_CHECK_STEPS: usize = 4096;
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+// Bytes per `m` reply, two hex digits each, well within the PacketSize in qSupported
+const MAX_MEMORY_READ: usize = 0x1F4;
+
+// The order of GDB's z80 target, which clients already know how to show
+const REGISTER_NAMES: [&str; 6] = ["af", "bc", "de", "hl", "sp", "pc"];
+
+/// GDB remote serial protocol server
+/// <https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html>
+///
+/// Registers are AF BC DE HL SP PC, 16-bit little endian.
+/// Memory reads do not trigger watchpoints.
+pub struct GdbServer {
+    pub debugger: Debugger,
+}
+
+/// Byte stream to a GDB client
+pub trait Client: Read + Write {
+    /// Checks for a pending interrupt byte without blocking
+    fn interrupted(&mut self) -> anyhow::Result<bool>;
+}
+
+struct TcpClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> GdbServer {
+        return GdbServer { debugger };
+    }
+
+    /// Waits for one client on localhost and serves it until it detaches
+    pub fn listen(&mut self, port: u16) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let mut client = TcpClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        return self.serve(&mut client);
+    }
+
+    pub fn serve(&mut self, client: &mut impl Client) -> anyhow::Result<()> {
+        while let Some(packet) = read_packet(client)? {
+            let response = match packet.as_bytes().first() {
+                Some(b'c') | Some(b's') => {
+                    let addr = packet.get(1..).filter(|addr| !addr.is_empty());
+                    match addr.map(|addr| u16::from_str_radix(addr, 16)).transpose() {
+                        Ok(addr) => {
+                            if let Some(addr) = addr {
+                                self.debugger.set_register("pc", addr)?;
+                            }
+                            self.resume(client, packet.starts_with('s'))?
+                        }
+                        Err(_) => "E01".to_string(),
+                    }
+                }
+                Some(b'D') => {
+                    send_packet(client, "OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => self.handle(&packet),
+            };
+            send_packet(client, &response)?;
+        }
+        return Ok(());
+    }
+
+    /// Runs until something stops the emulator and returns the stop reply
+    fn resume(&mut self, client: &mut impl Client, step: bool) -> anyhow::Result<String> {
+        if step {
+            return Ok(stop_reply(&self.debugger.step_n(1)));
+        }
+
+        loop {
+            let reason = self.debugger.continue_(Some(INTERRUPT_CHECK_STEPS));
+            if reason != StopReason::Limit {
+                return Ok(stop_reply(&reason));
+            }
+            if client.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    /// Answers everything but the run control packets, "E01" for bad requests
+    fn handle(&mut self, packet: &str) -> String {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let response = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "g" => Some(self.registers().iter().map(|val| hex_u16(*val)).collect()),
+            "G" => self.write_registers(args),
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|i| self.registers().get(i).map(|val| hex_u16(*val))),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "H" => Some("OK".to_string()),
+            "q" if args.starts_with("Supported") => Some("PacketSize=1000".to_string()),
+            "q" if args == "Attached" => Some("1".to_string()),
+            // Unsupported packets get an empty reply
+            _ => Some(String::new()),
+        };
+        return response.unwrap_or_else(|| "E01".to_string());
+    }
+
+    fn registers(&self) -> [u16; 6] {
+        let cpu = self.debugger.gb.cpu();
+        let r = &cpu.registers;
+        let af = (r.a as u16) << 8 | cpu.flag_registers.bits() as u16;
+        return [af, r.bc, r.de, r.hl, r.sp, r.pc];
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = parse_hex_bytes(args)?;
+        for (i, pair) in bytes.chunks_exact(2).take(REGISTER_NAMES.len()).enumerate() {
+            let val = u16::from_le_bytes([pair[0], pair[1]]);
+            self.debugger.set_register(REGISTER_NAMES[i], val).ok()?;
+        }
+        return Some("OK".to_string());
+    }
+
+    /// `P n=value`
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (i, val) = args.split_once('=')?;
+        let name = REGISTER_NAMES.get(usize::from_str_radix(i, 16).ok()?)?;
+        let bytes = parse_hex_bytes(val)?;
+        let val = u16::from_le_bytes([*bytes.first()?, *bytes.get(1).unwrap_or(&0)]);
+        self.debugger.set_register(name, val).ok()?;
+        return Some("OK".to_string());
+    }
+
+    /// `m addr,length`, up to what fits in a packet
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = parse_addr_len(args)?;
+        let len = len.min(MAX_MEMORY_READ);
+        let bus = &self.debugger.gb.cpu().bus;
+        return Some(
+            (0..len)
+                .map(|i| format!("{:02x}", bus.peek(addr.wrapping_add(i as u16))))
+                .collect(),
+        );
+    }
+
+    /// `M addr,length:bytes`
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = parse_addr_len(range)?;
+        let bytes = parse_hex_bytes(data)?;
+        if bytes.len() != len {
+            return None;
+        }
+        self.debugger.write_memory(addr, &bytes);
+        return Some("OK".to_string());
+    }
+
+    /// `Z type,addr,kind` inserts and `z type,addr,kind` removes.
+    /// Types 0 and 1 are PC breakpoints, 2-4 write, read and access watchpoints over `kind` bytes
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let len = u16::from_str_radix(fields.next()?, 16).ok()?.max(1);
+        let end = addr.saturating_add(len - 1);
+
+        let watch_kinds: &[WatchKind] = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.breakpoints.insert(Breakpoint::new(addr));
+                } else {
+                    self.debugger.breakpoints.remove(&Breakpoint::new(addr));
+                }
+                return Some("OK".to_string());
+            }
+            "2" => &[WatchKind::Write],
+            "3" => &[WatchKind::Read],
+            "4" => &[WatchKind::Read, WatchKind::Write],
+            _ => return Some(String::new()),
+        };
+        for kind in watch_kinds {
+            let watchpoint = Watchpoint::new(addr, end, *kind);
+            if insert {
+                self.debugger.gb.add_watchpoint(watchpoint);
+            } else {
+                self.debugger.gb.remove_watchpoint(&watchpoint);
+            }
+        }
+        return Some("OK".to_string());
+    }
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    return match reason {
+        StopReason::Watchpoint(hits) => {
+            let hit = &hits[0];
+            let kind = match hit.watchpoint.kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Write | WatchKind::Change => "watch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr)
+        }
+        _ => format!("S{:02x}", SIGTRAP),
+    };
+}
+
+impl Read for TcpClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.reader.read(buf);
+    }
+}
+
+impl Write for TcpClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.writer.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+impl Client for TcpClient {
+    fn interrupted(&mut self) -> anyhow::Result<bool> {
+        let mut byte = [0];
+        self.reader.get_ref().set_nonblocking(true)?;
+        let result = self.reader.read(&mut byte);
+        self.reader.get_ref().set_nonblocking(false)?;
+        return match result {
+            Ok(0) => anyhow::bail!("GDB client disconnected"),
+            Ok(_) => Ok(byte[0] == INTERRUPT),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+    }
+}
+
+/// Reads `$data#checksum` and acknowledges it, None once the client disconnects
+fn read_packet(client: &mut (impl Read + Write)) -> anyhow::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        if client.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        // acks and interrupts while stopped are ignored
+        if byte[0] != b'$' {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if client.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        client.read_exact(&mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if expected != Some(checksum_of(&data)) {
+            client.write_all(b"-")?;
+            continue;
+        }
+        client.write_all(b"+")?;
+        return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+    }
+}
+
+fn send_packet(writer: &mut impl Write, data: &str) -> anyhow::Result<()> {
+    write!(writer, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+    writer.flush()?;
+    return Ok(());
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    return data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+}
+
+fn hex_u16(val: u16) -> String {
+    let [low, high] = val.to_le_bytes();
+    return format!("{:02x}{:02x}", low, high);
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    return Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::Gb;
+    use crate::model::Model;
+    use crate::rom::tests::rom_with_code;
+
+    use std::io::Cursor;
+
+    /// Packets sent by the client up front, replies collected
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            return self.input.read(buf);
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.output.write(buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    impl Client for Script {
+        fn interrupted(&mut self) -> anyhow::Result<bool> {
+            return Ok(true);
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        return format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+    }
+
+    /// Everything the server writes in answer to `input`
+    fn serve(input: &str) -> String {
+        let gb = Gb::from_rom(rom_with_code(&[0x00, 0x00]), Some(Model::Dmg));
+        let mut server = GdbServer::new(Debugger::new(gb));
+        let mut script = Script {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        server.serve(&mut script).unwrap();
+        return String::from_utf8(script.output).unwrap();
+    }
+
+    #[test]
+    fn bad_checksum_is_refused() {
+        assert_eq!(serve("$?#00"), "-");
+        assert_eq!(
+            serve(&format!("$?#00{}", packet("?"))),
+            format!("-+{}", packet("S05"))
+        );
+    }
+
+    #[test]
+    fn resume_address_must_fit_in_16_bits() {
+        for request in ["c10000", "s10000", "cxyz", "s-1"] {
+            assert_eq!(
+                serve(&packet(request)),
+                format!("+{}", packet("E01")),
+                "{}",
+                request
+            );
+        }
+        assert_eq!(serve(&packet("s0100")), format!("+{}", packet("S05")));
+        assert_eq!(serve(&packet("c")), format!("+{}", packet("S02")));
+    }
+
+    #[test]
+    fn memory_reads_are_capped_to_a_packet() {
+        let reply = serve(&packet("m100,2"));
+        assert_eq!(reply, format!("+{}", packet("0000")));
+
+        let reply = serve(&packet("m0,1000"));
+        let data = &reply["+$".len()..reply.len() - "#00".len()];
+        assert_eq!(data.len(), MAX_MEMORY_READ * 2);
+    }
+
+    #[test]
+    fn detach_ends_the_session() {
+        let input = format!("{}{}", packet("D"), packet("?"));
+        assert_eq!(serve(&input), format!("+{}", packet("OK")));
+    }
+}
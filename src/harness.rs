@@ -0,0 +1,171 @@
+use crate::gb::Gb;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::screenshot::{load_png, save_png};
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+// Number of mismatching pixels listed in the report
+const REPORTED_PIXELS: usize = 10;
+
+/// Runs a ROM headlessly and compares the last frame with a reference image.
+/// Used for test ROMs whose result is visual, like dmg-acid2.
+pub struct ScreenshotTest<'a> {
+    pub rom_path: &'a str,
+    pub reference_path: &'a str,
+    pub frames: u64,
+    // where the actual and diff images are written on failure
+    pub output_dir: &'a str,
+}
+
+pub struct Mismatch {
+    pub x: usize,
+    pub y: usize,
+    pub expected: [u8; 4],
+    pub actual: [u8; 4],
+}
+
+pub struct Diff {
+    pub width: usize,
+    pub height: usize,
+    pub mismatches: Vec<Mismatch>,
+    // matching pixels dimmed, mismatching pixels in red
+    pub image: Vec<u8>,
+}
+
+impl Diff {
+    pub fn is_match(&self) -> bool {
+        return self.mismatches.is_empty();
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let total = self.width * self.height;
+        writeln!(
+            report,
+            "{} of {} pixels differ ({:.2}%)",
+            self.mismatches.len(),
+            total,
+            self.mismatches.len() as f64 * 100.0 / total as f64
+        )
+        .unwrap();
+
+        if let Some((min_x, min_y, max_x, max_y)) = self.bounds() {
+            writeln!(
+                report,
+                "differences within ({}, {})-({}, {})",
+                min_x, min_y, max_x, max_y
+            )
+            .unwrap();
+        }
+        for m in self.mismatches.iter().take(REPORTED_PIXELS) {
+            writeln!(
+                report,
+                "  ({:3}, {:3}) expected #{:02X}{:02X}{:02X} actual #{:02X}{:02X}{:02X}",
+                m.x,
+                m.y,
+                m.expected[0],
+                m.expected[1],
+                m.expected[2],
+                m.actual[0],
+                m.actual[1],
+                m.actual[2]
+            )
+            .unwrap();
+        }
+        if self.mismatches.len() > REPORTED_PIXELS {
+            writeln!(report, "  ...").unwrap();
+        }
+        return report;
+    }
+
+    /// Bounding box of the mismatching pixels
+    fn bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        let first = self.mismatches.first()?;
+        let mut bounds = (first.x, first.y, first.x, first.y);
+        for m in &self.mismatches {
+            bounds.0 = bounds.0.min(m.x);
+            bounds.1 = bounds.1.min(m.y);
+            bounds.2 = bounds.2.max(m.x);
+            bounds.3 = bounds.3.max(m.y);
+        }
+        return Some(bounds);
+    }
+}
+
+/// Compares two RGBA images of the same size, alpha is ignored
+pub fn compare(expected: &[u8], actual: &[u8], width: usize, height: usize) -> Diff {
+    let mut mismatches = Vec::new();
+    let mut image = Vec::with_capacity(width * height * 4);
+
+    for (i, (e, a)) in expected.chunks(4).zip(actual.chunks(4)).enumerate() {
+        if e[..3] == a[..3] {
+            let gray = ((e[0] as u16 + e[1] as u16 + e[2] as u16) / 3 / 4 + 0xC0) as u8;
+            image.extend_from_slice(&[gray, gray, gray, 0xFF]);
+        } else {
+            image.extend_from_slice(&[0xFF, 0x00, 0x00, 0xFF]);
+            mismatches.push(Mismatch {
+                x: i % width,
+                y: i / width,
+                expected: [e[0], e[1], e[2], e[3]],
+                actual: [a[0], a[1], a[2], a[3]],
+            });
+        }
+    }
+
+    return Diff {
+        width,
+        height,
+        mismatches,
+        image,
+    };
+}
+
+impl ScreenshotTest<'_> {
+    /// Returns the report as an error when the images differ
+    pub fn run(&self) -> anyhow::Result<()> {
+        let mut gb = Gb::new(self.rom_path);
+        for _ in 0..self.frames {
+            gb.run_frame();
+        }
+        let actual = gb.screenshot();
+
+        let (width, height, expected) = load_png(self.reference_path)?;
+        if (width as usize, height as usize) != (SCREEN_WIDTH, SCREEN_HEIGHT) {
+            anyhow::bail!(
+                "reference {} is {}x{}, expected {}x{}",
+                self.reference_path,
+                width,
+                height,
+                SCREEN_WIDTH,
+                SCREEN_HEIGHT
+            );
+        }
+
+        let diff = compare(&expected, &actual, SCREEN_WIDTH, SCREEN_HEIGHT);
+        if diff.is_match() {
+            return Ok(());
+        }
+
+        let name = Path::new(self.rom_path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        fs::create_dir_all(self.output_dir)?;
+        let actual_path = format!("{}/{}.actual.png", self.output_dir, name);
+        let diff_path = format!("{}/{}.diff.png", self.output_dir, name);
+        save_png(&actual_path, width, height, &actual)?;
+        save_png(&diff_path, width, height, &diff.image)?;
+
+        anyhow::bail!(
+            "{} does not match {} after {} frames\n{}actual: {}\ndiff: {}",
+            self.rom_path,
+            self.reference_path,
+            self.frames,
+            diff.report(),
+            actual_path,
+            diff_path
+        );
+    }
+}
@@ -0,0 +1,86 @@
+/// CGB VRAM DMA, HDMA1-HDMA5 ($FF51-$FF55)
+/// <https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers>
+///
+/// General purpose DMA copies everything at once while the CPU is halted,
+/// HBlank DMA copies 16 bytes at the start of each HBlank.
+#[derive(Clone)]
+pub struct Hdma {
+    pub source: u16,
+    // offset in VRAM, $0000-$1FF0
+    pub destination: u16,
+    // remaining 16-byte blocks minus one, as read back from HDMA5
+    pub remaining: u8,
+    pub hblank_active: bool,
+}
+
+pub enum HdmaRequest {
+    // copy every block now
+    GeneralPurpose,
+    // wait for HBlank
+    HBlank,
+    Cancel,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        return Hdma {
+            source: 0,
+            destination: 0,
+            remaining: 0x7F,
+            hblank_active: false,
+        };
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // HDMA1-HDMA4 are write only
+            0xFF51..=0xFF54 => 0xFF,
+            // bit 7 is 0 while an HBlank transfer is active, $FF once done
+            0xFF55 => ((!self.hblank_active) as u8) << 7 | self.remaining,
+            _ => panic!("Hdma::read: invalid address: 0x{:04X}", addr),
+        }
+    }
+
+    /// Returns the transfer requested by a write to HDMA5
+    pub fn write(&mut self, addr: u16, val: u8) -> Option<HdmaRequest> {
+        match addr {
+            0xFF51 => self.source = (self.source & 0x00FF) | (val as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (val & 0xF0) as u16,
+            0xFF53 => self.destination = (self.destination & 0x00FF) | ((val & 0x1F) as u16) << 8,
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (val & 0xF0) as u16,
+            0xFF55 => {
+                // Writing bit 7 = 0 during an HBlank transfer stops it
+                if self.hblank_active && val & 0x80 == 0 {
+                    self.hblank_active = false;
+                    return Some(HdmaRequest::Cancel);
+                }
+                self.remaining = val & 0x7F;
+                if val & 0x80 != 0 {
+                    self.hblank_active = true;
+                    return Some(HdmaRequest::HBlank);
+                }
+                return Some(HdmaRequest::GeneralPurpose);
+            }
+            _ => panic!("Hdma::write: invalid address: 0x{:04X}", addr),
+        }
+        return None;
+    }
+
+    /// Called after a block was copied
+    pub fn advance(&mut self) {
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1FF0;
+        if self.remaining == 0 {
+            self.remaining = 0x7F;
+            self.hblank_active = false;
+        } else {
+            self.remaining -= 1;
+        }
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Hdma {
+        return Hdma::new();
+    }
+}
@@ -0,0 +1,7 @@
+/// Interrupt flags shared by IE ($FFFF) and IF ($FF0F)
+/// <https://gbdev.io/pandocs/Interrupts.html>
+pub const VBLANK: u8 = 1 << 0;
+pub const LCD_STAT: u8 = 1 << 1;
+pub const TIMER: u8 = 1 << 2;
+pub const SERIAL: u8 = 1 << 3;
+pub const JOYPAD: u8 = 1 << 4;
@@ -0,0 +1,104 @@
+use crate::interrupt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Bit of the button in the pressed mask, d-pad in the low nibble
+    fn mask(&self) -> u8 {
+        return match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        };
+    }
+}
+
+// Joypads of the SGB multiplayer mode
+pub const MAX_PLAYERS: usize = 4;
+
+/// P1/JOYP ($FF00)
+/// <https://gbdev.io/pandocs/Joypad_Input.html>
+///
+/// Up to 4 joypads are connected when the SGB multiplayer mode is on.
+#[derive(Clone)]
+pub struct Joypad {
+    // P15 (bit 5) selects the buttons, P14 (bit 4) the d-pad, active low
+    pub select: u8,
+    pub pressed: [u8; MAX_PLAYERS],
+    // Joypads read through P1, 1 unless an SGB enabled multiplayer
+    pub players: u8,
+    pub current: u8,
+    // Interrupts requested since the bus last collected them
+    pub interrupts: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        return Joypad {
+            select: 0x30,
+            pressed: [0; MAX_PLAYERS],
+            players: 1,
+            current: 0,
+            interrupts: 0,
+        };
+    }
+
+    pub fn read(&self) -> u8 {
+        let pressed = self.pressed[self.current as usize];
+        let mut lines = 0;
+        if self.select & 0x10 == 0 {
+            lines |= pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            lines |= pressed >> 4;
+        }
+        // With nothing selected the SGB returns the current joypad as $F - id
+        if self.select == 0x30 && self.players > 1 {
+            lines = self.current;
+        }
+        return 0xC0 | self.select | (!lines & 0x0F);
+    }
+
+    pub fn write(&mut self, val: u8) {
+        let select = val & 0x30;
+        // The SGB moves on to the next joypad when P15 goes back high
+        if self.players > 1 && self.select & 0x20 == 0 && select == 0x30 {
+            self.current = (self.current + 1) % self.players;
+        }
+        self.select = select;
+    }
+
+    pub fn set_pressed(&mut self, player: usize, button: Button, pressed: bool) {
+        let before = self.read();
+        if pressed {
+            self.pressed[player] |= button.mask();
+        } else {
+            self.pressed[player] &= !button.mask();
+        }
+        // Requested when a selected line goes from high to low
+        if before & !self.read() & 0x0F != 0 {
+            self.interrupts |= interrupt::JOYPAD;
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        return Joypad::new();
+    }
+}
@@ -1,7 +1,34 @@
+#![allow(clippy::needless_return, clippy::unused_unit)]
+
+pub mod apu;
+pub mod blip;
 pub mod bus;
+pub mod compat_palette;
+pub mod coverage;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod dma;
+pub mod doctor;
 pub mod gb;
+pub mod gdb;
+pub mod harness;
+pub mod hdma;
+pub mod interrupt;
+pub mod joypad;
 pub mod mbc;
+pub mod model;
+pub mod palette;
 pub mod ppu;
+pub mod profiler;
 pub mod ram;
+pub mod recorder;
+pub mod rewind;
 pub mod rom;
+pub mod screenshot;
+pub mod sgb;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod watchpoint;
+pub mod wav;
@@ -1,10 +1,386 @@
+use gb::apu::Channel;
+use gb::coverage::Coverage;
+use gb::debugger::{Command, Debugger};
+use gb::doctor::{compare, Comparison};
 use gb::gb::Gb;
+use gb::gdb::GdbServer;
+use gb::model::Model;
+use gb::palette::Palette;
+use gb::recorder::MAX_FRAME_SKIP;
+use gb::rewind;
+use std::env;
 use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process;
+
+/// Usage: gb [ROM] [--model dmg|mgb|sgb|cgb|agb] [--steps N | --frames N] [--screenshot FILE] [--sgb-screenshot FILE] [--palette NAME|FILE]
+///           [--trace FILE|-] [--symbols FILE.sym] [--profile FILE|-] [--profile-folded FILE] [--cdl FILE] [--record FILE.gif|FILE.y4m] [--frame-skip N] [--wav FILE] [--sample-rate HZ] [--no-high-pass]
+///           [--mute CH,..] [--solo CH] [--channel-wav PREFIX]
+struct Options {
+    rom_path: String,
+    model: Option<Model>,
+    steps: usize,
+    frames: Option<u64>,
+    screenshot_path: Option<String>,
+    sgb_screenshot_path: Option<String>,
+    palette: Option<Palette>,
+    trace_path: Option<String>,
+    symbols_path: Option<String>,
+    profile_path: Option<String>,
+    folded_profile_path: Option<String>,
+    cdl_path: Option<String>,
+    record_path: Option<String>,
+    frame_skip: u32,
+    wav_path: Option<String>,
+    sample_rate: Option<u32>,
+    high_pass: bool,
+    muted: Vec<Channel>,
+    solo: Option<Channel>,
+    channel_wav_prefix: Option<String>,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        rom_path: "test_roms/cpu_instrs.gb".to_string(),
+        model: None,
+        steps: 5,
+        frames: None,
+        screenshot_path: None,
+        sgb_screenshot_path: None,
+        palette: None,
+        trace_path: None,
+        symbols_path: None,
+        profile_path: None,
+        folded_profile_path: None,
+        cdl_path: None,
+        record_path: None,
+        frame_skip: 0,
+        wav_path: None,
+        sample_rate: None,
+        high_pass: true,
+        muted: Vec::new(),
+        solo: None,
+        channel_wav_prefix: None,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => {
+                let name = next_value(&mut args, &arg);
+                options.model =
+                    Some(Model::parse(&name).unwrap_or_else(|| panic!("unknown model: {}", name)))
+            }
+            "--steps" => options.steps = next_value(&mut args, &arg).parse().unwrap(),
+            "--frames" => options.frames = Some(next_value(&mut args, &arg).parse().unwrap()),
+            "--screenshot" => options.screenshot_path = Some(next_value(&mut args, &arg)),
+            "--sgb-screenshot" => options.sgb_screenshot_path = Some(next_value(&mut args, &arg)),
+            "--palette" => {
+                options.palette = Some(Palette::from_arg(&next_value(&mut args, &arg)).unwrap())
+            }
+            "--trace" => options.trace_path = Some(next_value(&mut args, &arg)),
+            "--symbols" => options.symbols_path = Some(next_value(&mut args, &arg)),
+            "--profile" => options.profile_path = Some(next_value(&mut args, &arg)),
+            "--cdl" => options.cdl_path = Some(next_value(&mut args, &arg)),
+            "--profile-folded" => options.folded_profile_path = Some(next_value(&mut args, &arg)),
+            "--record" => options.record_path = Some(next_value(&mut args, &arg)),
+            "--frame-skip" => {
+                options.frame_skip = next_value(&mut args, &arg).parse().unwrap();
+                if options.frame_skip > MAX_FRAME_SKIP {
+                    panic!("--frame-skip must be at most {}", MAX_FRAME_SKIP);
+                }
+            }
+            "--wav" => options.wav_path = Some(next_value(&mut args, &arg)),
+            "--sample-rate" => {
+                options.sample_rate = Some(next_value(&mut args, &arg).parse().unwrap())
+            }
+            "--no-high-pass" => options.high_pass = false,
+            "--mute" => {
+                options.muted = next_value(&mut args, &arg)
+                    .split(',')
+                    .map(parse_channel)
+                    .collect()
+            }
+            "--solo" => options.solo = Some(parse_channel(&next_value(&mut args, &arg))),
+            "--channel-wav" => options.channel_wav_prefix = Some(next_value(&mut args, &arg)),
+            flag if flag.starts_with("--") => panic!("unknown option: {}", flag),
+            _ => options.rom_path = arg,
+        }
+    }
+
+    options
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> String {
+    args.next()
+        .unwrap_or_else(|| panic!("missing value for {}", flag))
+}
+
+fn parse_channel(val: &str) -> Channel {
+    val.parse()
+        .ok()
+        .and_then(Channel::from_number)
+        .unwrap_or_else(|| panic!("invalid channel: {} (expected 1-4)", val))
+}
+
+/// Usage: gb doctor ROM REFERENCE_LOG [--context N]
+///
+/// Compares our trace with a Gameboy Doctor log and stops at the first difference
+fn run_doctor(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut context = 10;
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => context = next_value(&mut args, &arg).parse().unwrap(),
+            flag if flag.starts_with("--") => panic!("unknown option: {}", flag),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        panic!("usage: gb doctor ROM REFERENCE_LOG [--context N]");
+    }
+
+    let mut gb = Gb::with_model(&paths[0], Model::Dmg);
+    let reference = BufReader::new(File::open(&paths[1]).unwrap());
+    match compare(&mut gb, reference, context).unwrap() {
+        Comparison::Match { lines } => println!("All {} lines match", lines),
+        Comparison::Divergence(divergence) => {
+            println!("{}", divergence);
+            process::exit(1);
+        }
+    }
+}
+
+/// Usage: gb debug ROM [--model dmg|mgb|sgb|cgb|agb] [--symbols FILE.sym]
+///
+/// Reads debugger commands from stdin, an empty line repeats the last one.
+/// Keeps a rewind buffer so `back` and `rewind` can undo execution.
+/// Symbols are loaded from the ROM's path with a .sym extension when present
+fn run_debug(args: &[String]) {
+    let mut rom_path = None;
+    let mut model = None;
+    let mut symbols_path = None;
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => {
+                let name = next_value(&mut args, &arg);
+                model =
+                    Some(Model::parse(&name).unwrap_or_else(|| panic!("unknown model: {}", name)))
+            }
+            "--symbols" => symbols_path = Some(next_value(&mut args, &arg)),
+            flag if flag.starts_with("--") => panic!("unknown option: {}", flag),
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path =
+        rom_path.unwrap_or_else(|| panic!("usage: gb debug ROM [--model NAME] [--symbols FILE]"));
+
+    let mut gb = match model {
+        Some(model) => Gb::with_model(&rom_path, model),
+        None => Gb::new(&rom_path),
+    };
+    let symbols_path = symbols_path.or_else(|| {
+        let path = Path::new(&rom_path).with_extension("sym");
+        path.exists().then(|| path.to_string_lossy().into_owned())
+    });
+    if let Some(symbols_path) = symbols_path {
+        gb.load_symbols(&symbols_path).unwrap();
+        println!(
+            "Loaded {} symbols from {}",
+            gb.symbols().map_or(0, |s| s.len()),
+            symbols_path
+        );
+    }
+    gb.enable_rewind(rewind::DEFAULT_CAPACITY, rewind::DEFAULT_INTERVAL);
+    let mut debugger = Debugger::new(gb);
+    println!(
+        "{}",
+        debugger.describe_stop(&gb::debugger::StopReason::Step)
+    );
+
+    let stdin = std::io::stdin();
+    let mut last = None;
+    loop {
+        print!("(gb) ");
+        std::io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+
+        let command = if line.trim().is_empty() {
+            match &last {
+                Some(command) => Ok(command),
+                None => continue,
+            }
+        } else {
+            Command::parse(&line, debugger.gb.symbols()).map(|command| &*last.insert(command))
+        };
+        let output = command.and_then(|command| {
+            if *command == Command::Quit {
+                process::exit(0);
+            }
+            debugger.run_command(command)
+        });
+        match output {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+/// Usage: gb gdb ROM [--port N] [--model dmg|mgb|sgb|cgb|agb]
+///
+/// Waits for a GDB remote protocol client on localhost, port 2345 by default
+fn run_gdb(args: &[String]) {
+    let mut rom_path = None;
+    let mut model = None;
+    let mut port = 2345;
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => {
+                let name = next_value(&mut args, &arg);
+                model =
+                    Some(Model::parse(&name).unwrap_or_else(|| panic!("unknown model: {}", name)))
+            }
+            "--port" => port = next_value(&mut args, &arg).parse().unwrap(),
+            flag if flag.starts_with("--") => panic!("unknown option: {}", flag),
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path =
+        rom_path.unwrap_or_else(|| panic!("usage: gb gdb ROM [--port N] [--model NAME]"));
+
+    let gb = match model {
+        Some(model) => Gb::with_model(&rom_path, model),
+        None => Gb::new(&rom_path),
+    };
+    let mut server = GdbServer::new(Debugger::new(gb));
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    server.listen(port).unwrap();
+}
 
 fn main() {
-    let mut gb = Gb::new("test_roms/cpu_instrs.gb");
-    for _ in 0..5 {
-        gb.step();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("doctor") => {
+            run_doctor(&args[1..]);
+            return;
+        }
+        Some("debug") => {
+            run_debug(&args[1..]);
+            return;
+        }
+        Some("gdb") => {
+            run_gdb(&args[1..]);
+            return;
+        }
+        _ => {}
+    }
+
+    let options = parse_args();
+
+    let mut gb = match options.model {
+        Some(model) => Gb::with_model(&options.rom_path, model),
+        None => Gb::new(&options.rom_path),
+    };
+    if let Some(sample_rate) = options.sample_rate {
+        gb.set_sample_rate(sample_rate);
+    }
+    gb.apu_mut().high_pass = options.high_pass;
+    if options.wav_path.is_some() {
+        gb.start_audio_capture();
+    }
+    for channel in &options.muted {
+        gb.apu_mut().set_muted(*channel, true);
+    }
+    if let Some(channel) = options.solo {
+        gb.apu_mut().solo(channel);
+    }
+    if options.channel_wav_prefix.is_some() {
+        gb.apu_mut().start_channel_capture();
+    }
+
+    if let Some(palette) = options.palette {
+        gb.set_palette(palette);
+    }
+    if let Some(symbols_path) = &options.symbols_path {
+        gb.load_symbols(symbols_path).unwrap();
+    }
+    if let Some(trace_path) = &options.trace_path {
+        gb.start_trace(trace_path).unwrap();
+    }
+    if let Some(record_path) = &options.record_path {
+        gb.start_recording(record_path, options.frame_skip).unwrap();
+    }
+    if options.profile_path.is_some() || options.folded_profile_path.is_some() {
+        gb.start_profiling();
+    }
+    if let Some(path) = &options.cdl_path {
+        // coverage from earlier runs is kept
+        let coverage = Path::new(path)
+            .exists()
+            .then(|| Coverage::load(path, gb.rom_len()).unwrap());
+        gb.start_coverage(coverage);
+    }
+
+    match options.frames {
+        Some(frames) => {
+            for _ in 0..frames {
+                gb.run_frame();
+            }
+        }
+        None => {
+            for _ in 0..options.steps {
+                gb.step();
+            }
+        }
+    }
+
+    gb.stop_trace().unwrap();
+    gb.stop_recording().unwrap();
+    if let (Some(path), Some(coverage)) = (&options.cdl_path, gb.stop_coverage()) {
+        coverage.save(path).unwrap();
+        let summary = coverage.summary();
+        println!(
+            "Coverage: {} opcode, {} operand, {} data, {} DMA, {} unused bytes",
+            summary.opcode, summary.operand, summary.data, summary.dma, summary.unused
+        );
+    }
+    if let Some(profiler) = gb.stop_profiling() {
+        if let Some(path) = &options.profile_path {
+            // the 30 hottest functions and addresses
+            let report = profiler.report(gb.symbols(), 30);
+            if path == "-" {
+                print!("{}", report);
+            } else {
+                fs::write(path, report).unwrap();
+            }
+        }
+        if let Some(path) = &options.folded_profile_path {
+            profiler
+                .write_folded(gb.symbols(), File::create(path).unwrap())
+                .unwrap();
+        }
+    }
+    if let Some(screenshot_path) = &options.screenshot_path {
+        gb.save_screenshot(screenshot_path).unwrap();
+    }
+    if let Some(path) = &options.sgb_screenshot_path {
+        gb.save_sgb_screenshot(path).unwrap();
+    }
+
+    if let Some(wav_path) = &options.wav_path {
+        gb.save_audio_wav(wav_path).unwrap();
+    }
+    if let Some(prefix) = &options.channel_wav_prefix {
+        gb.save_channel_wavs(prefix).unwrap();
     }
 
     /*
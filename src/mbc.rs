@@ -1,5 +1,7 @@
 use crate::rom::{CartridgeType, Rom};
 
+use std::rc::Rc;
+
 // TODO: move to defines
 pub const KB: usize = 1024;
 
@@ -10,6 +12,38 @@ pub const KB: usize = 1024;
 pub trait Mbc {
     fn read(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, val: u8) -> ();
+
+    /// Copy for snapshots, the ROM itself is shared
+    fn clone_box(&self) -> Box<dyn Mbc>;
+
+    /// Size of the ROM in bytes
+    fn rom_len(&self) -> usize;
+
+    /// Bank mapped at $4000-$7FFF
+    fn rom_bank(&self) -> usize {
+        return 1;
+    }
+
+    /// Bank mapped at $A000-$BFFF
+    fn ram_bank(&self) -> usize {
+        return 0;
+    }
+
+    /// Offset in the ROM of the byte mapped at `addr`
+    fn rom_offset(&self, addr: u16) -> Option<usize> {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => self.rom_bank() * 0x4000 + (addr - 0x4000) as usize,
+            _ => return None,
+        };
+        return Some(offset).filter(|offset| *offset < self.rom_len());
+    }
+}
+
+impl Clone for Box<dyn Mbc> {
+    fn clone(&self) -> Box<dyn Mbc> {
+        return self.clone_box();
+    }
 }
 
 pub fn new_mbc(rom: Rom) -> Box<dyn Mbc> {
@@ -24,25 +58,31 @@ pub fn new_mbc(rom: Rom) -> Box<dyn Mbc> {
 ///
 /// Optionally up to 8 KiB of RAM could be connected at $A000-BFFF,
 /// using a discrete logic decoder in place of a full MBC chip
+#[derive(Clone)]
 pub struct RomOnly {
-    rom: Rom,
+    rom: Rc<Rom>,
 }
 
 impl RomOnly {
     pub fn new(rom: Rom) -> RomOnly {
-        return RomOnly { rom };
+        return RomOnly { rom: Rc::new(rom) };
     }
 }
 
 impl Mbc for RomOnly {
+    fn clone_box(&self) -> Box<dyn Mbc> {
+        return Box::new(self.clone());
+    }
+
+    fn rom_len(&self) -> usize {
+        return self.rom.value.len();
+    }
+
     fn read(&self, addr: u16) -> u8 {
-        if addr <= 0xBFFF {
-            return self.rom.value[addr as usize];
-        } else if addr > 0xBFFF {
-            panic!("RomOnly::read: invalid address: 0x{:04X}", addr);
-        } else {
+        if addr > 0xBFFF {
             panic!("RomOnly::read: invalid address: 0x{:04X}", addr);
         }
+        return self.rom.value[addr as usize];
     }
 
     #[allow(unused_variables)]
@@ -52,29 +92,35 @@ impl Mbc for RomOnly {
 }
 
 /// max 2MByte ROM and/or 32 KiB RAM
+#[derive(Clone)]
 pub struct Mbc1 {
-    rom: Rom,
+    rom: Rc<Rom>,
     ram: [u8; 32 * KB],
 }
 
 impl Mbc1 {
     pub fn new(rom: Rom) -> Mbc1 {
         return Mbc1 {
-            rom,
+            rom: Rc::new(rom),
             ram: [0; 32 * KB],
         };
     }
 }
 
 impl Mbc for Mbc1 {
+    fn clone_box(&self) -> Box<dyn Mbc> {
+        return Box::new(self.clone());
+    }
+
+    fn rom_len(&self) -> usize {
+        return self.rom.value.len();
+    }
+
     fn read(&self, addr: u16) -> u8 {
-        if addr <= 0xBFFF {
-            return self.rom.value[addr as usize];
-        } else if addr > 0xBFFF {
-            panic!("RomOnly::read: invalid address: 0x{:04X}", addr);
-        } else {
+        if addr > 0xBFFF {
             panic!("RomOnly::read: invalid address: 0x{:04X}", addr);
         }
+        return self.rom.value[addr as usize];
     }
 
     #[allow(unused_variables)]
@@ -0,0 +1,116 @@
+use crate::rom::Rom;
+
+/// Emulated console
+/// <https://gbdev.io/pandocs/Power_Up_Sequence.html>
+///
+/// The boot ROM is not run, the CPU starts at $0100 with the registers and IO
+/// state each model's boot ROM leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    // Game Boy
+    Dmg,
+    // Game Boy Pocket / Light
+    Mgb,
+    // Super Game Boy
+    Sgb,
+    // Game Boy Color
+    Cgb,
+    // Game Boy Advance
+    Agb,
+}
+
+/// Registers left by the boot ROM
+pub struct BootRegisters {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+}
+
+impl Model {
+    pub fn parse(name: &str) -> Option<Model> {
+        return match name.to_ascii_lowercase().as_str() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            "agb" => Some(Model::Agb),
+            _ => None,
+        };
+    }
+
+    /// CGB for games with CGB support, DMG otherwise
+    pub fn for_rom(rom: &Rom) -> Model {
+        return if rom.cgb_flag { Model::Cgb } else { Model::Dmg };
+    }
+
+    /// The model has CGB hardware, which runs DMG games in compatibility mode
+    pub fn is_cgb(&self) -> bool {
+        return matches!(self, Model::Cgb | Model::Agb);
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        return *self == Model::Sgb;
+    }
+
+    pub fn boot_registers(&self, rom: &Rom) -> BootRegisters {
+        match self {
+            Model::Dmg | Model::Mgb => {
+                // H and C are cleared when the header checksum is 0
+                let f = if rom.header_checksum == 0 { 0x80 } else { 0xB0 };
+                let a = if *self == Model::Dmg { 0x01 } else { 0xFF };
+                return BootRegisters {
+                    af: (a << 8) | f,
+                    bc: 0x0013,
+                    de: 0x00D8,
+                    hl: 0x014D,
+                };
+            }
+            Model::Sgb => {
+                return BootRegisters {
+                    af: 0x0100,
+                    bc: 0x0014,
+                    de: 0x0000,
+                    hl: 0xC060,
+                };
+            }
+            Model::Cgb | Model::Agb => {
+                let (b, de, hl) = if rom.cgb_flag {
+                    (0x00, 0xFF56, 0x000D)
+                } else {
+                    // DMG games are left with the title checksum used to pick the palette
+                    let b = if rom.licensed_by_nintendo() {
+                        rom.title_checksum()
+                    } else {
+                        0x00
+                    };
+                    let hl = if b == 0x43 || b == 0x58 {
+                        0x991A
+                    } else {
+                        0x007C
+                    };
+                    (b, 0x0008, hl)
+                };
+
+                if *self == Model::Cgb {
+                    return BootRegisters {
+                        af: 0x1180,
+                        bc: (b as u16) << 8,
+                        de,
+                        hl,
+                    };
+                }
+
+                // The AGB boot ROM ends with INC B, which also sets the flags
+                let b = b.wrapping_add(1);
+                let f = ((b == 0) as u8) << 7 | ((b & 0x0F == 0) as u8) << 5;
+                return BootRegisters {
+                    af: 0x1100 | f as u16,
+                    bc: (b as u16) << 8,
+                    de,
+                    hl,
+                };
+            }
+        }
+    }
+}
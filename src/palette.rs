@@ -0,0 +1,116 @@
+use std::fs;
+
+/// Maps the four DMG shades (0 = lightest) to RGB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    pub const GREY: Palette = Palette::from_hex([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+    // The pea-green LCD of the original DMG
+    pub const DMG: Palette = Palette::from_hex([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
+    pub const POCKET: Palette = Palette::from_hex([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]);
+    pub const HIGH_CONTRAST: Palette = Palette::from_hex([0xFFFFFF, 0xD0D0D0, 0x303030, 0x000000]);
+
+    pub const PRESETS: [(&'static str, Palette); 4] = [
+        ("grey", Palette::GREY),
+        ("dmg", Palette::DMG),
+        ("pocket", Palette::POCKET),
+        ("high-contrast", Palette::HIGH_CONTRAST),
+    ];
+
+    pub const fn from_hex(colors: [u32; 4]) -> Palette {
+        let mut rgb = [[0; 3]; 4];
+        let mut i = 0;
+        while i < 4 {
+            rgb[i] = [
+                (colors[i] >> 16) as u8,
+                (colors[i] >> 8) as u8,
+                colors[i] as u8,
+            ];
+            i += 1;
+        }
+        return Palette { colors: rgb };
+    }
+
+    pub fn preset(name: &str) -> Option<Palette> {
+        return Palette::PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .map(|(_, palette)| *palette);
+    }
+
+    /// Four hex colors, lightest first, separated by whitespace or commas.
+    /// `;` starts a comment.
+    ///
+    /// ```text
+    /// ; my palette
+    /// #E0F8D0 #88C070
+    /// #346856 #081820
+    /// ```
+    pub fn parse(text: &str) -> anyhow::Result<Palette> {
+        let colors = text
+            .lines()
+            .map(|line| line.split(';').next().unwrap_or(""))
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                let hex = token.trim_start_matches('#').trim_start_matches("0x");
+                if hex.len() != 6 {
+                    anyhow::bail!("invalid color: {}", token);
+                }
+                return Ok(u32::from_str_radix(hex, 16)?);
+            })
+            .collect::<anyhow::Result<Vec<u32>>>()?;
+
+        if colors.len() != 4 {
+            anyhow::bail!("expected 4 colors, found {}", colors.len());
+        }
+        return Ok(Palette::from_hex([
+            colors[0], colors[1], colors[2], colors[3],
+        ]));
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Palette> {
+        return Palette::parse(&fs::read_to_string(path)?);
+    }
+
+    /// Preset name or path of a palette file
+    pub fn from_arg(arg: &str) -> anyhow::Result<Palette> {
+        return match Palette::preset(arg) {
+            Some(palette) => Ok(palette),
+            None => Palette::load(arg),
+        };
+    }
+
+    /// Converts a framebuffer of shades (0-3) to RGBA pixels
+    pub fn to_rgba(&self, framebuffer: &[u8]) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(framebuffer.len() * 4);
+        for shade in framebuffer {
+            rgba.extend_from_slice(&self.colors[*shade as usize & 0x03]);
+            rgba.push(0xFF);
+        }
+        return rgba;
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        return Palette::GREY;
+    }
+}
+
+/// Converts a CGB framebuffer of 15-bit colors to RGBA pixels
+/// <https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only>
+pub fn rgb555_to_rgba(framebuffer: &[u16]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(framebuffer.len() * 4);
+    for color in framebuffer {
+        for shift in [0, 5, 10] {
+            let c = ((color >> shift) & 0x1F) as u8;
+            rgba.push((c << 3) | (c >> 2));
+        }
+        rgba.push(0xFF);
+    }
+    return rgba;
+}
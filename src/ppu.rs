@@ -1 +1,499 @@
-pub struct Ppu {}
+use crate::compat_palette::CompatPalettes;
+use crate::interrupt;
+use crate::mbc::KB;
+
+pub const OAM_SIZE: usize = 0xA0;
+pub const VRAM_BANK_SIZE: usize = 8 * KB;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u16 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+const OAM_SCAN_DOTS: u16 = 80;
+// Mode 3 length varies between 172 and 289 dots, the minimum is used
+const DRAWING_DOTS: u16 = 172;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+/// <https://gbdev.io/pandocs/STAT.html>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// ppu(picture processing unit)
+/// <https://gbdev.io/pandocs/Graphics.html>
+///
+/// Renders a whole scanline at the start of HBlank.
+#[derive(Clone)]
+pub struct Ppu {
+    // Bank 1 only exists in CGB mode
+    pub vram: [u8; 2 * VRAM_BANK_SIZE],
+    // Object Attribute Memory: 40 sprites * 4 bytes
+    pub oam: [u8; OAM_SIZE],
+    // Shade (0-3) of each pixel after the BGP/OBP palettes
+    pub framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // 15-bit color of each pixel, filled instead of `framebuffer` in CGB mode
+    pub cgb_framebuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+
+    pub cgb: bool,
+    // CGB hardware running a DMG game: shades are colored through BG palette 0
+    // and OBJ palettes 0/1, and `cgb_framebuffer` is filled as well
+    pub dmg_compat: bool,
+    // $FF4F: VBK
+    pub vbk: u8,
+    // 8 palettes * 4 colors * 2 bytes (little endian RGB555)
+    pub bg_palette: [u8; 64],
+    pub obj_palette: [u8; 64],
+    // $FF68/$FF6A: palette index, bit 7 enables auto increment
+    pub bcps: u8,
+    pub ocps: u8,
+
+    // $FF40: LCD control
+    pub lcdc: u8,
+    // $FF41: interrupt sources of STAT, the mode and LYC=LY bits are computed
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+
+    pub mode: Mode,
+    // Interrupts requested since the bus last collected them
+    pub interrupts: u8,
+    // Set when a visible line enters HBlank, taken by the bus for HBlank DMA
+    pub hblank_started: bool,
+    // Number of completed frames
+    pub frame: u64,
+
+    dot: u16,
+    // Dots counted while the LCD is off, so frames keep advancing
+    off_dots: u32,
+    window_line: u8,
+    stat_line: bool,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        return Ppu {
+            vram: [0; 2 * VRAM_BANK_SIZE],
+            oam: [0; OAM_SIZE],
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            cgb_framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+
+            cgb: false,
+            dmg_compat: false,
+            vbk: 0,
+            bg_palette: [0; 64],
+            obj_palette: [0; 64],
+            bcps: 0,
+            ocps: 0,
+
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+
+            mode: Mode::HBlank,
+            interrupts: 0,
+            hblank_started: false,
+            frame: 0,
+
+            dot: 0,
+            off_dots: 0,
+            window_line: 0,
+            stat_line: false,
+        };
+    }
+
+    /// Loads the colorization the CGB boot ROM picks for DMG games
+    pub fn set_compat_palettes(&mut self, palettes: &CompatPalettes) {
+        self.dmg_compat = true;
+        for (i, color) in palettes.bg.iter().enumerate() {
+            self.bg_palette[i * 2..i * 2 + 2].copy_from_slice(&color.to_le_bytes());
+        }
+        for (i, color) in palettes.obj0.iter().chain(palettes.obj1.iter()).enumerate() {
+            self.obj_palette[i * 2..i * 2 + 2].copy_from_slice(&color.to_le_bytes());
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        return self.lcdc & 0x80 != 0;
+    }
+
+    /// $8000-$9FFF through the bank selected by VBK
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        return self.vram[self.vram_offset(addr)];
+    }
+
+    pub fn write_vram(&mut self, addr: u16, val: u8) {
+        let offset = self.vram_offset(addr);
+        self.vram[offset] = val;
+    }
+
+    fn vram_offset(&self, addr: u16) -> usize {
+        return (self.vbk & 0x01) as usize * VRAM_BANK_SIZE + (addr - 0x8000) as usize;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.lcdc,
+            0xFF41 => 0x80 | self.stat | ((self.ly == self.lyc) as u8) << 2 | self.mode as u8,
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            // CGB registers read as $FF in DMG mode
+            0xFF4F | 0xFF68..=0xFF6B if !self.cgb => 0xFF,
+            0xFF4F => 0xFE | self.vbk,
+            0xFF68 => 0x40 | self.bcps,
+            0xFF69 => self.bg_palette[(self.bcps & 0x3F) as usize],
+            0xFF6A => 0x40 | self.ocps,
+            0xFF6B => self.obj_palette[(self.ocps & 0x3F) as usize],
+            _ => panic!("Ppu::read: invalid address: 0x{:04X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF40 => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = val;
+                if was_enabled && !self.lcd_enabled() {
+                    // LY is reset and the PPU stays in mode 0 while off
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.off_dots = 0;
+                    self.window_line = 0;
+                    self.mode = Mode::HBlank;
+                } else if !was_enabled && self.lcd_enabled() {
+                    self.dot = 0;
+                    self.mode = Mode::OamScan;
+                }
+            }
+            0xFF41 => self.stat = val & 0x78,
+            0xFF42 => self.scy = val,
+            0xFF43 => self.scx = val,
+            0xFF44 => (), // read only
+            0xFF45 => self.lyc = val,
+            0xFF47 => self.bgp = val,
+            0xFF48 => self.obp0 = val,
+            0xFF49 => self.obp1 = val,
+            0xFF4A => self.wy = val,
+            0xFF4B => self.wx = val,
+            0xFF4F | 0xFF68..=0xFF6B if !self.cgb => (),
+            0xFF4F => self.vbk = val & 0x01,
+            0xFF68 => self.bcps = val & 0xBF,
+            0xFF69 => {
+                self.bg_palette[(self.bcps & 0x3F) as usize] = val;
+                self.bcps = auto_increment(self.bcps);
+            }
+            0xFF6A => self.ocps = val & 0xBF,
+            0xFF6B => {
+                self.obj_palette[(self.ocps & 0x3F) as usize] = val;
+                self.ocps = auto_increment(self.ocps);
+            }
+            _ => panic!("Ppu::write: invalid address: 0x{:04X}", addr),
+        }
+        self.update_stat_interrupt();
+    }
+
+    /// Advances by one M-cycle (4 dots)
+    pub fn tick(&mut self) {
+        for _ in 0..4 {
+            self.tick_dot();
+        }
+    }
+
+    fn tick_dot(&mut self) {
+        if !self.lcd_enabled() {
+            self.off_dots += 1;
+            if self.off_dots == DOTS_PER_FRAME {
+                self.off_dots = 0;
+                self.frame += 1;
+            }
+            return;
+        }
+
+        self.dot += 1;
+        if self.ly < SCREEN_HEIGHT as u8 {
+            if self.dot == OAM_SCAN_DOTS {
+                self.set_mode(Mode::Drawing);
+            } else if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+                self.render_scanline();
+                self.set_mode(Mode::HBlank);
+                self.hblank_started = true;
+            }
+        }
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+            if self.ly == SCREEN_HEIGHT as u8 {
+                self.set_mode(Mode::VBlank);
+                self.interrupts |= interrupt::VBLANK;
+                self.frame += 1;
+            } else if self.ly == LINES_PER_FRAME {
+                self.ly = 0;
+                self.window_line = 0;
+                self.set_mode(Mode::OamScan);
+            } else if self.ly < SCREEN_HEIGHT as u8 {
+                self.set_mode(Mode::OamScan);
+            }
+            self.update_stat_interrupt();
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.update_stat_interrupt();
+    }
+
+    /// The STAT interrupt fires on the rising edge of the OR of all enabled sources
+    fn update_stat_interrupt(&mut self) {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OamScan)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank);
+
+        if line && !self.stat_line {
+            self.interrupts |= interrupt::LCD_STAT;
+        }
+        self.stat_line = line;
+    }
+
+    /// Color index (0-3) of a pixel in the tile at the given VRAM offset
+    fn tile_pixel(&self, tile_addr: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[tile_addr + y as usize * 2];
+        let high = self.vram[tile_addr + y as usize * 2 + 1];
+        let bit = 7 - x;
+        return (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+    }
+
+    /// VRAM offset of a BG/window tile, LCDC bit 4 selects the addressing mode
+    fn bg_tile_addr(&self, tile: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            return tile as usize * 16;
+        }
+        // $8800 method: signed index relative to $9000
+        return (0x1000 + (tile as i8 as i32) * 16) as usize;
+    }
+
+    fn render_scanline(&mut self) {
+        let ly = self.ly;
+        // BG/window color index before the palette, needed for sprite priority
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        // CGB BG map attributes of each pixel
+        let mut bg_attributes = [0u8; SCREEN_WIDTH];
+
+        // LCDC bit 0 disables BG and window on DMG, on CGB it only takes away their priority
+        if self.cgb || self.lcdc & 0x01 != 0 {
+            let window_visible = self.lcdc & 0x20 != 0 && self.wy <= ly && self.wx <= 166;
+            for x in 0..SCREEN_WIDTH {
+                let in_window = window_visible && x as i16 >= self.wx as i16 - 7;
+                let (map_base, px, py) = if in_window {
+                    let map_base = if self.lcdc & 0x40 != 0 {
+                        0x1C00
+                    } else {
+                        0x1800
+                    };
+                    let px = (x as i16 - (self.wx as i16 - 7)) as u8;
+                    (map_base, px, self.window_line)
+                } else {
+                    let map_base = if self.lcdc & 0x08 != 0 {
+                        0x1C00
+                    } else {
+                        0x1800
+                    };
+                    (
+                        map_base,
+                        self.scx.wrapping_add(x as u8),
+                        self.scy.wrapping_add(ly),
+                    )
+                };
+                let map_addr = map_base + (py as usize / 8) * 32 + px as usize / 8;
+                let tile = self.vram[map_addr];
+
+                // https://gbdev.io/pandocs/Tile_Maps.html#bg-map-attributes-cgb-mode-only
+                let attributes = if self.cgb {
+                    self.vram[VRAM_BANK_SIZE + map_addr]
+                } else {
+                    0
+                };
+                let bank = (attributes >> 3) as usize & 0x01;
+                let tx = if attributes & 0x20 != 0 {
+                    7 - px % 8
+                } else {
+                    px % 8
+                };
+                let ty = if attributes & 0x40 != 0 {
+                    7 - py % 8
+                } else {
+                    py % 8
+                };
+
+                let tile_addr = bank * VRAM_BANK_SIZE + self.bg_tile_addr(tile);
+                bg_colors[x] = self.tile_pixel(tile_addr, tx, ty);
+                bg_attributes[x] = attributes;
+            }
+            if window_visible {
+                self.window_line += 1;
+            }
+        }
+
+        let sprites = if self.lcdc & 0x02 != 0 {
+            self.sprite_pixels()
+        } else {
+            [None; SCREEN_WIDTH]
+        };
+
+        let start = ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            let bg_color = bg_colors[x];
+            let sprite = sprites[x].filter(|(_, attributes)| {
+                if bg_color == 0 {
+                    return true;
+                }
+                if self.cgb {
+                    // LCDC bit 0 cleared: sprites are always on top
+                    return self.lcdc & 0x01 == 0
+                        || (attributes & 0x80 == 0 && bg_attributes[x] & 0x80 == 0);
+                }
+                return attributes & 0x80 == 0;
+            });
+
+            if self.cgb {
+                self.cgb_framebuffer[start + x] = match sprite {
+                    Some((color, attributes)) => {
+                        cgb_color(&self.obj_palette, attributes & 0x07, color)
+                    }
+                    None => cgb_color(&self.bg_palette, bg_attributes[x] & 0x07, bg_color),
+                };
+            } else {
+                let (shade, palette) = match sprite {
+                    Some((color, attributes)) => {
+                        let obp1 = attributes & 0x10 != 0;
+                        let palette = if obp1 { self.obp1 } else { self.obp0 };
+                        (palette_shade(palette, color), Some(obp1 as u8))
+                    }
+                    None => (palette_shade(self.bgp, bg_color), None),
+                };
+                self.framebuffer[start + x] = shade;
+                if self.dmg_compat {
+                    self.cgb_framebuffer[start + x] = match palette {
+                        Some(obj_palette) => cgb_color(&self.obj_palette, obj_palette, shade),
+                        None => cgb_color(&self.bg_palette, 0, shade),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Color index and attributes of the visible sprite pixel at each X
+    /// <https://gbdev.io/pandocs/OAM.html>
+    fn sprite_pixels(&self) -> [Option<(u8, u8)>; SCREEN_WIDTH] {
+        let ly = self.ly as i16;
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+
+        // The first 10 sprites in OAM order that overlap the line
+        let mut sprites: Vec<usize> = (0..40)
+            .filter(|i| {
+                let y = self.oam[i * 4] as i16 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        // On DMG smaller X wins and ties go to the earlier OAM entry,
+        // CGB only uses the OAM order
+        if !self.cgb {
+            sprites.sort_by_key(|i| self.oam[i * 4 + 1]);
+        }
+
+        let mut pixels = [None; SCREEN_WIDTH];
+        for i in sprites {
+            let y = self.oam[i * 4] as i16 - 16;
+            let x = self.oam[i * 4 + 1] as i16 - 8;
+            let mut tile = self.oam[i * 4 + 2];
+            let attributes = self.oam[i * 4 + 3];
+            let y_flip = attributes & 0x40 != 0;
+            let x_flip = attributes & 0x20 != 0;
+            let bank = if self.cgb {
+                (attributes >> 3) as usize & 0x01
+            } else {
+                0
+            };
+
+            let mut row = (ly - y) as u8;
+            if y_flip {
+                row = height as u8 - 1 - row;
+            }
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let tile_addr = bank * VRAM_BANK_SIZE + tile as usize * 16 + (row as usize / 8) * 16;
+
+            for col in 0..8 {
+                let screen_x = x + col;
+                if !(0..SCREEN_WIDTH as i16).contains(&screen_x) {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                if pixels[screen_x].is_some() {
+                    continue;
+                }
+                let px = if x_flip { 7 - col as u8 } else { col as u8 };
+                let color = self.tile_pixel(tile_addr, px, row % 8);
+                // A lower priority sprite never shows through an opaque one
+                if color != 0 {
+                    pixels[screen_x] = Some((color, attributes));
+                }
+            }
+        }
+        return pixels;
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        return Ppu::new();
+    }
+}
+
+/// BCPS/OCPS advance after a write to BCPD/OCPD when bit 7 is set
+fn auto_increment(index: u8) -> u8 {
+    if index & 0x80 == 0 {
+        return index;
+    }
+    return 0x80 | ((index + 1) & 0x3F);
+}
+
+/// <https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only>
+fn cgb_color(palette_ram: &[u8; 64], palette: u8, color: u8) -> u16 {
+    let index = palette as usize * 8 + color as usize * 2;
+    return u16::from_le_bytes([palette_ram[index], palette_ram[index + 1]]) & 0x7FFF;
+}
+
+/// <https://gbdev.io/pandocs/Palettes.html>
+fn palette_shade(palette: u8, color: u8) -> u8 {
+    return (palette >> (color * 2)) & 0x03;
+}
@@ -0,0 +1,301 @@
+use crate::cpu::OPCODE_CYCLES;
+use crate::symbols::Symbols;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+
+// Deeper calls are counted in the innermost kept frame, for code that
+// leaves the stack unbalanced by popping return addresses
+const MAX_STACK_DEPTH: usize = 64;
+
+/// Code location: the bank mapped at the address, when it is in the cartridge
+pub type Address = (Option<usize>, u16);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub instructions: u64,
+    // machine cycles
+    pub cycles: u64,
+}
+
+impl Sample {
+    fn add(&mut self, other: Sample) -> () {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+/// Counts executed instructions and their cycles per address and per call stack
+///
+/// Calls are followed by their opcodes rather than PC, which lets conditional ones
+/// be told apart by the extra cycles of a taken branch.
+#[derive(Default)]
+pub struct Profiler {
+    pub samples: HashMap<Address, Sample>,
+    // call targets from the outermost, mapped to the samples of the innermost
+    stacks: HashMap<Vec<Address>, Sample>,
+    call_stack: Vec<Address>,
+    // calls past MAX_STACK_DEPTH that have not returned yet
+    overflow: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        return Profiler::default();
+    }
+
+    /// Records an executed instruction, `next` is where execution continues
+    pub fn record(&mut self, addr: Address, opcode: u8, cycles: u8, next: Address) -> () {
+        let sample = Sample {
+            instructions: 1,
+            cycles: cycles as u64,
+        };
+        self.samples.entry(addr).or_default().add(sample);
+        match self.stacks.get_mut(&self.call_stack) {
+            Some(stack) => stack.add(sample),
+            None => {
+                self.stacks.insert(self.call_stack.clone(), sample);
+            }
+        }
+
+        let taken = cycles > OPCODE_CYCLES[opcode as usize];
+        let call = match opcode {
+            0xCD => true,
+            0xC4 | 0xCC | 0xD4 | 0xDC => taken,
+            _ => opcode & 0xC7 == 0xC7,
+        };
+        if call {
+            self.enter(next);
+            return;
+        }
+        let ret = match opcode {
+            0xC9 | 0xD9 => true,
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => taken,
+            _ => false,
+        };
+        if ret {
+            self.leave();
+        }
+    }
+
+    /// Records the dispatch of an interrupt, which is followed like a call to its vector
+    pub fn interrupt(&mut self, vector: Address) -> () {
+        self.enter(vector);
+    }
+
+    fn enter(&mut self, target: Address) -> () {
+        if self.call_stack.len() < MAX_STACK_DEPTH {
+            self.call_stack.push(target);
+        } else {
+            self.overflow += 1;
+        }
+    }
+
+    fn leave(&mut self) -> () {
+        if self.overflow > 0 {
+            self.overflow -= 1;
+        } else {
+            self.call_stack.pop();
+        }
+    }
+
+    pub fn total(&self) -> Sample {
+        let mut total = Sample::default();
+        for sample in self.samples.values() {
+            total.add(*sample);
+        }
+        return total;
+    }
+
+    /// Samples per function, the label before each address without its local part.
+    /// Without symbols every address is its own function
+    pub fn functions(&self, symbols: Option<&Symbols>) -> Vec<(String, Sample)> {
+        let mut functions: HashMap<String, Sample> = HashMap::new();
+        for (addr, sample) in &self.samples {
+            functions
+                .entry(function_name(symbols, *addr))
+                .or_default()
+                .add(*sample);
+        }
+        let mut functions: Vec<(String, Sample)> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        return functions;
+    }
+
+    /// The `top` functions and addresses by cycles
+    pub fn report(&self, symbols: Option<&Symbols>, top: usize) -> String {
+        let total = self.total();
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total.cycles.max(1) as f64;
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{} instructions, {} M-cycles",
+            total.instructions, total.cycles
+        );
+
+        if symbols.is_some() {
+            let _ = writeln!(
+                report,
+                "\n{:>12} {:>7} {:>12}  function",
+                "cycles", "%", "instructions"
+            );
+            for (name, sample) in self.functions(symbols).iter().take(top) {
+                let _ = writeln!(
+                    report,
+                    "{:>12} {:>6.2}% {:>12}  {}",
+                    sample.cycles,
+                    percent(sample.cycles),
+                    sample.instructions,
+                    name
+                );
+            }
+        }
+
+        let mut addresses: Vec<(&Address, &Sample)> = self.samples.iter().collect();
+        addresses.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+        let _ = writeln!(
+            report,
+            "\n{:>12} {:>7} {:>12}  address",
+            "cycles", "%", "instructions"
+        );
+        for ((bank, addr), sample) in addresses.iter().take(top) {
+            let mut name = format_address((*bank, *addr));
+            if let Some(location) = symbols.and_then(|symbols| symbols.locate_in(*bank, *addr)) {
+                let _ = write!(name, " <{}>", location);
+            }
+            let _ = writeln!(
+                report,
+                "{:>12} {:>6.2}% {:>12}  {}",
+                sample.cycles,
+                percent(sample.cycles),
+                sample.instructions,
+                name
+            );
+        }
+        return report;
+    }
+
+    /// One `outer;inner cycles` line per call stack, the input of flamegraph.pl and inferno
+    pub fn write_folded(
+        &self,
+        symbols: Option<&Symbols>,
+        mut sink: impl Write,
+    ) -> anyhow::Result<()> {
+        // stacks through different addresses of a function are merged
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (stack, sample) in &self.stacks {
+            let mut frames = vec!["root".to_string()];
+            frames.extend(stack.iter().map(|addr| function_name(symbols, *addr)));
+            *folded.entry(frames.join(";")).or_default() += sample.cycles;
+        }
+        let mut lines: Vec<(String, u64)> = folded.into_iter().collect();
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(sink, "{} {}", stack, cycles)?;
+        }
+        sink.flush()?;
+        return Ok(());
+    }
+}
+
+fn function_name(symbols: Option<&Symbols>, (bank, addr): Address) -> String {
+    let location = symbols.and_then(|symbols| symbols.locate_in(bank, addr));
+    return match location {
+        // local labels belong to the global label before them
+        Some(location) => location.label.split('.').next().unwrap_or("").to_string(),
+        None => format_address((bank, addr)),
+    };
+}
+
+fn format_address((bank, addr): Address) -> String {
+    return match bank {
+        Some(bank) => format!("${:02X}:{:04X}", bank, addr),
+        None => format!("${:04X}", addr),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: u16 = 0x0150;
+    const RECURSE: u16 = 0x0200;
+    const CALLS: usize = 70;
+    const SYMBOLS: &str = "00:0150 Main\n00:0200 Recurse\n00:0204 Recurse.done";
+
+    fn at(addr: u16) -> Address {
+        return (Some(0), addr);
+    }
+
+    /// Main calls Recurse, which calls itself past MAX_STACK_DEPTH before all return
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new();
+        profiler.record(at(MAIN), 0x00, 1, at(MAIN + 1));
+        profiler.record(at(MAIN + 1), 0xCD, 6, at(RECURSE));
+        for _ in 0..CALLS {
+            profiler.record(at(RECURSE), 0xCD, 6, at(RECURSE));
+        }
+        profiler.record(at(RECURSE + 3), 0x00, 1, at(RECURSE + 4));
+        for _ in 0..=CALLS {
+            profiler.record(at(RECURSE + 4), 0xC9, 4, at(RECURSE + 4));
+        }
+        profiler.record(at(MAIN + 4), 0x00, 1, at(MAIN + 5));
+        return profiler;
+    }
+
+    #[test]
+    fn folded_stacks_past_the_depth_limit() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+        let mut folded = Vec::new();
+        profile().write_folded(Some(&symbols), &mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        let lines: Vec<&str> = folded.lines().collect();
+
+        let stack = |depth: usize| format!("root{}", ";Recurse".repeat(depth));
+        assert_eq!(lines.len(), MAX_STACK_DEPTH + 1);
+        // the return after the deepest calls still gets back to Main
+        assert_eq!(lines[0], "root 8");
+        // each level makes one call and returns once
+        assert_eq!(lines[1], format!("{} 10", stack(1)));
+        assert!(lines.contains(&format!("{} 10", stack(MAX_STACK_DEPTH - 1)).as_str()));
+        // the calls past the limit, their returns and the innermost code
+        let overflow = CALLS + 1 - MAX_STACK_DEPTH;
+        let innermost = overflow * 6 + 1 + (overflow + 1) * 4;
+        assert_eq!(
+            lines[MAX_STACK_DEPTH],
+            format!("{} {}", stack(MAX_STACK_DEPTH), innermost)
+        );
+    }
+
+    #[test]
+    fn functions_sum_their_addresses() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+        let profiler = profile();
+        let recurse = Sample {
+            instructions: (CALLS + 1 + CALLS + 1) as u64,
+            cycles: (CALLS * 6 + 1 + (CALLS + 1) * 4) as u64,
+        };
+        let main = Sample {
+            instructions: 3,
+            cycles: 8,
+        };
+        assert_eq!(
+            profiler.functions(Some(&symbols)),
+            vec![("Recurse".to_string(), recurse), ("Main".to_string(), main)]
+        );
+        assert_eq!(profiler.total().cycles, recurse.cycles + main.cycles);
+
+        // without symbols, by address
+        assert_eq!(
+            profiler.functions(None)[0],
+            (
+                "$00:0200".to_string(),
+                Sample {
+                    instructions: CALLS as u64,
+                    cycles: CALLS as u64 * 6,
+                }
+            )
+        );
+    }
+}
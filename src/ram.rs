@@ -1,15 +1,65 @@
 use crate::mbc::KB;
 
+const WORK_BANK_SIZE: usize = 4 * KB;
+
+#[derive(Clone)]
 pub struct Ram {
-    pub work: [u8; 8 * KB],
+    // 8 banks of 4 KiB, bank 0 is fixed at $C000 and $D000 switches between 1-7 in CGB mode
+    pub work: [u8; 8 * WORK_BANK_SIZE],
     pub high: [u8; 8 * KB],
+    // $FF70: SVBK
+    pub svbk: u8,
 }
 
 impl Ram {
     pub fn new() -> Ram {
         return Ram {
-            work: [0; 8 * KB],
+            work: [0; 8 * WORK_BANK_SIZE],
             high: [0; 8 * KB],
+            svbk: 0,
+        };
+    }
+
+    /// Bank 0 selects bank 1
+    fn work_bank(&self) -> usize {
+        return match self.svbk & 0x07 {
+            0 => 1,
+            bank => bank as usize,
+        };
+    }
+
+    fn work_offset(&self, addr: u16) -> usize {
+        // ECHO RAM: Nintendo prohibits developers from using this memory range.
+        let addr = match addr {
+            0xE000..=0xFDFF => addr - 0x2000,
+            _ => addr,
+        };
+        return match addr {
+            0xC000..=0xCFFF => (addr - 0xC000) as usize,
+            0xD000..=0xDFFF => self.work_bank() * WORK_BANK_SIZE + (addr - 0xD000) as usize,
+            _ => panic!("Ram: invalid address: 0x{:04X}", addr),
         };
     }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        return match addr {
+            0xC000..=0xFDFF => self.work[self.work_offset(addr)],
+            0xFF80..=0xFFFE => self.high[(addr - 0xFF80) as usize],
+            _ => panic!("Ram::read: invalid address: 0x{:04X}", addr),
+        };
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xC000..=0xFDFF => self.work[self.work_offset(addr)] = val,
+            0xFF80..=0xFFFE => self.high[(addr - 0xFF80) as usize] = val,
+            _ => panic!("Ram::write: invalid address: 0x{:04X}", addr),
+        }
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Ram {
+        return Ram::new();
+    }
 }
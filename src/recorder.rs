@@ -0,0 +1,206 @@
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// 4194304 Hz / 70224 dots per frame
+const FRAME_RATE_NUM: i64 = 4_194_304;
+const FRAME_RATE_DEN: i64 = 70_224;
+// Browsers show GIF frames with shorter delays for 10 cs instead
+const MIN_GIF_DELAY: i64 = 2;
+
+/// Largest `frame_skip`, one frame kept about every minute
+pub const MAX_FRAME_SKIP: u32 = 3599;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    Gif,
+    // Raw YUV4MPEG2 stream, e.g. `ffmpeg -i out.y4m out.mp4`
+    Y4m,
+}
+
+impl RecordFormat {
+    pub fn from_path(path: &str) -> Option<RecordFormat> {
+        let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        return match extension.as_str() {
+            "gif" => Some(RecordFormat::Gif),
+            "y4m" => Some(RecordFormat::Y4m),
+            _ => None,
+        };
+    }
+}
+
+enum Encoder {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Y4m(BufWriter<File>),
+}
+
+/// Appends completed frames to an animated GIF or a Y4M video
+pub struct Recorder {
+    encoder: Encoder,
+    // frames dropped between two recorded frames
+    frame_skip: u32,
+    skipped: u32,
+    // Time not yet covered by GIF frame delays, in 1/100 s scaled by FRAME_RATE_NUM.
+    // Negative when delays were rounded up
+    gif_time: i64,
+    // first write error, reported by finish()
+    error: Option<anyhow::Error>,
+}
+
+impl Recorder {
+    pub fn new(path: &str, format: RecordFormat, frame_skip: u32) -> anyhow::Result<Recorder> {
+        let frame_skip = frame_skip.min(MAX_FRAME_SKIP);
+        let mut writer = BufWriter::new(File::create(path)?);
+        let encoder = match format {
+            RecordFormat::Gif => {
+                let mut encoder =
+                    gif::Encoder::new(writer, SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Encoder::Gif(encoder)
+            }
+            RecordFormat::Y4m => {
+                // The frame rate is lowered by the skipped frames
+                writeln!(
+                    writer,
+                    "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                    SCREEN_WIDTH,
+                    SCREEN_HEIGHT,
+                    FRAME_RATE_NUM,
+                    FRAME_RATE_DEN * (frame_skip as i64 + 1)
+                )?;
+                Encoder::Y4m(writer)
+            }
+        };
+
+        return Ok(Recorder {
+            encoder,
+            frame_skip,
+            skipped: 0,
+            gif_time: 0,
+            error: None,
+        });
+    }
+
+    /// Picks the format from the file extension (.gif or .y4m)
+    pub fn from_path(path: &str, frame_skip: u32) -> anyhow::Result<Recorder> {
+        let format = match RecordFormat::from_path(path) {
+            Some(format) => format,
+            None => anyhow::bail!("unknown recording format: {} (expected .gif or .y4m)", path),
+        };
+        return Recorder::new(path, format, frame_skip);
+    }
+
+    /// Called with the RGBA pixels of every completed frame
+    pub fn push_frame(&mut self, rgba: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        if self.skipped < self.frame_skip {
+            self.skipped += 1;
+            return;
+        }
+        self.skipped = 0;
+
+        let result = match &mut self.encoder {
+            Encoder::Gif(_) => self.write_gif_frame(rgba),
+            Encoder::Y4m(writer) => write_y4m_frame(writer, rgba),
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    /// Flushes the file and returns the first error hit while recording
+    pub fn finish(self) -> anyhow::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        match self.encoder {
+            Encoder::Gif(encoder) => {
+                encoder.into_inner()?.flush()?;
+            }
+            Encoder::Y4m(mut writer) => writer.flush()?,
+        }
+        return Ok(());
+    }
+
+    fn write_gif_frame(&mut self, rgba: &[u8]) -> anyhow::Result<()> {
+        // 100 * (skip + 1) * 70224 / 4194304 centiseconds, about 1.67 per frame
+        self.gif_time += 100 * (self.frame_skip as i64 + 1) * FRAME_RATE_DEN;
+        // Delays are raised to the minimum, and frames are dropped while earlier
+        // ones still run ahead, which keeps unskipped recordings at about 50 fps
+        if self.gif_time <= 0 {
+            return Ok(());
+        }
+        let delay = ((self.gif_time + FRAME_RATE_NUM / 2) / FRAME_RATE_NUM)
+            .clamp(MIN_GIF_DELAY, u16::MAX as i64);
+        self.gif_time -= delay * FRAME_RATE_NUM;
+
+        let mut frame = indexed_frame(rgba);
+        frame.delay = delay as u16;
+
+        if let Encoder::Gif(encoder) = &mut self.encoder {
+            encoder.write_frame(&frame)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Uses a local palette of the exact colors when they fit in 256 entries,
+/// otherwise falls back to quantization
+fn indexed_frame(rgba: &[u8]) -> gif::Frame<'static> {
+    let mut palette: Vec<u8> = Vec::new();
+    let mut indices: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut pixels = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+
+    for p in rgba.chunks(4) {
+        let color = [p[0], p[1], p[2]];
+        let index = match indices.get(&color) {
+            Some(index) => *index,
+            None => {
+                if indices.len() == 256 {
+                    let mut rgba = rgba.to_vec();
+                    return gif::Frame::from_rgba_speed(
+                        SCREEN_WIDTH as u16,
+                        SCREEN_HEIGHT as u16,
+                        &mut rgba,
+                        10,
+                    );
+                }
+                let index = indices.len() as u8;
+                indices.insert(color, index);
+                palette.extend_from_slice(&color);
+                index
+            }
+        };
+        pixels.push(index);
+    }
+
+    let mut frame =
+        gif::Frame::from_indexed_pixels(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, pixels, None);
+    frame.palette = Some(palette);
+    return frame;
+}
+
+/// Planar 4:4:4 frame in BT.601 studio range
+fn write_y4m_frame<W: Write>(writer: &mut W, rgba: &[u8]) -> anyhow::Result<()> {
+    let mut y_plane = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+    let mut u_plane = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+    let mut v_plane = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+
+    for p in rgba.chunks(4) {
+        let (r, g, b) = (p[0] as f32, p[1] as f32, p[2] as f32);
+        y_plane.push((16.0 + (65.738 * r + 129.057 * g + 25.064 * b) / 256.0).round() as u8);
+        u_plane.push((128.0 + (-37.945 * r - 74.494 * g + 112.439 * b) / 256.0).round() as u8);
+        v_plane.push((128.0 + (112.439 * r - 94.154 * g - 18.285 * b) / 256.0).round() as u8);
+    }
+
+    writer.write_all(b"FRAME\n")?;
+    writer.write_all(&y_plane)?;
+    writer.write_all(&u_plane)?;
+    writer.write_all(&v_plane)?;
+    return Ok(());
+}
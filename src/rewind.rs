@@ -0,0 +1,113 @@
+use crate::cpu::Snapshot;
+use crate::joypad::Button;
+
+use std::collections::VecDeque;
+
+pub const DEFAULT_CAPACITY: usize = 60;
+// frames between snapshots
+pub const DEFAULT_INTERVAL: u64 = 10;
+
+/// In-memory save state
+#[derive(Clone)]
+pub struct SaveState {
+    // instructions executed before this state
+    pub instructions: u64,
+    // CPU steps before this state, also counting interrupt dispatches and HALT cycles
+    pub steps: u64,
+    pub frame: u64,
+    pub cpu: Snapshot,
+}
+
+/// A button change, applied before the CPU step with this count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Input {
+    pub step: u64,
+    pub player: usize,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+/// Ring buffer of periodic save states plus the inputs since the oldest one
+///
+/// Any earlier point in the buffer is reached by loading the snapshot before it
+/// and running forward again with the logged inputs, which is deterministic
+/// since nothing else from outside reaches the emulated hardware.
+pub struct Rewind {
+    snapshots: VecDeque<SaveState>,
+    capacity: usize,
+    interval: u64,
+    inputs: Vec<Input>,
+}
+
+impl Rewind {
+    /// Keeps `capacity` snapshots, one every `interval` frames
+    pub fn new(capacity: usize, interval: u64) -> Rewind {
+        let capacity = capacity.max(1);
+        return Rewind {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            interval: interval.max(1),
+            inputs: Vec::new(),
+        };
+    }
+
+    /// Whether a snapshot should be taken on reaching `frame`
+    pub fn due(&self, frame: u64) -> bool {
+        return self
+            .snapshots
+            .back()
+            .is_none_or(|last| frame >= last.frame + self.interval);
+    }
+
+    /// Adds a snapshot, dropping the oldest one and its inputs when full
+    pub fn push(&mut self, state: SaveState) -> () {
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(state);
+        if let Some(oldest) = self.oldest() {
+            let start = oldest.steps;
+            self.inputs.retain(|input| input.step >= start);
+        }
+    }
+
+    pub fn log_input(&mut self, input: Input) -> () {
+        self.inputs.push(input);
+    }
+
+    pub fn oldest(&self) -> Option<&SaveState> {
+        return self.snapshots.front();
+    }
+
+    /// The most recent snapshot that `accept` is true for
+    pub fn latest(&self, accept: impl Fn(&SaveState) -> bool) -> Option<&SaveState> {
+        return self.snapshots.iter().rev().find(|state| accept(state));
+    }
+
+    /// Inputs logged at or after `step`, in order
+    pub fn inputs_from(&self, step: u64) -> &[Input] {
+        let start = self.inputs.partition_point(|input| input.step < step);
+        return &self.inputs[start..];
+    }
+
+    /// Forgets everything after `step`, which becomes the present
+    pub fn truncate(&mut self, step: u64) -> () {
+        while self.snapshots.back().is_some_and(|last| last.steps > step) {
+            self.snapshots.pop_back();
+        }
+        self.inputs.retain(|input| input.step < step);
+    }
+
+    pub fn clear(&mut self) -> () {
+        self.snapshots.clear();
+        self.inputs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.snapshots.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.snapshots.is_empty();
+    }
+}
@@ -1,6 +1,5 @@
 use std::fmt;
-use std::fs::File;
-use std::io::BufReader;
+use std::io::BufRead;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -147,7 +146,7 @@ impl Default for Rom {
 }
 
 impl Rom {
-    pub fn new(reader: &mut BufReader<File>) -> Rom {
+    pub fn new(reader: &mut (impl BufRead + Seek)) -> Rom {
         let mut rom = Rom::default();
 
         reader.seek(SeekFrom::Start(ENTRY_POINT_START)).unwrap();
@@ -161,7 +160,8 @@ impl Rom {
 
         reader.seek(SeekFrom::Start(CGB_FLAG)).unwrap();
         rom.cgb_flag = match reader.take(1).bytes().next() {
-            Some(Ok(0x80)) => false,
+            // The game supports CGB enhancements, but is backwards compatible
+            Some(Ok(0x80)) => true,
             Some(Ok(0xC0)) => true,
             Some(Ok(_unknown)) => false,
             Some(Err(e)) => panic!("error occured while reading the CGB Flag {}", e),
@@ -324,6 +324,19 @@ impl Rom {
         return rom;
         //return Rom;
     }
+
+    /// Sum of the 16 bytes at $0134-$0143, used by the CGB boot ROM
+    pub fn title_checksum(&self) -> u8 {
+        return self.value[TITLE_START as usize..=CGB_FLAG as usize]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+    }
+
+    /// https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+    pub fn licensed_by_nintendo(&self) -> bool {
+        return self.old_licensee_code == 0x01
+            || (self.old_licensee_code == 0x33 && self.new_licensee_code == *b"01");
+    }
 }
 
 fn bytes_to_hex(bytes: &[u8]) -> String {
@@ -332,3 +345,62 @@ fn bytes_to_hex(bytes: &[u8]) -> String {
         .map(|&b| format!("{:02X} ", b))
         .collect::<String>()
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// 32 KiB ROM with `code` at the entry point and a valid header checksum
+    pub fn rom_with_code(code: &[u8]) -> Rom {
+        let mut image = vec![0; 0x8000];
+        image[ENTRY_POINT_START as usize..][..code.len()].copy_from_slice(code);
+        image[HEADER_CHECKSUM as usize] = image[TITLE_START as usize..HEADER_CHECKSUM as usize]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_sub(*b).wrapping_sub(1));
+        return Rom::new(&mut Cursor::new(image));
+    }
+
+    /// Header of a game with `title`, enough for the title checksum
+    pub fn rom_with_title(title: &[u8], old_licensee_code: u8) -> Rom {
+        let mut rom = Rom {
+            value: vec![0; 0x150],
+            old_licensee_code,
+            ..Default::default()
+        };
+        rom.value[TITLE_START as usize..TITLE_START as usize + title.len()].copy_from_slice(title);
+        rom.title[..title.len()].copy_from_slice(title);
+        return rom;
+    }
+
+    #[test]
+    fn title_checksum_sums_title_bytes() {
+        assert_eq!(rom_with_title(b"TETRIS", 0x01).title_checksum(), 0xDB);
+        assert_eq!(rom_with_title(b"POKEMON RED", 0x01).title_checksum(), 0x14);
+
+        // the CGB flag at $0143 is part of the sum
+        let mut rom = rom_with_title(b"TETRIS", 0x01);
+        rom.value[CGB_FLAG as usize] = 0x80;
+        assert_eq!(rom.title_checksum(), 0x5B);
+    }
+
+    #[test]
+    fn loads_from_memory() {
+        let rom = rom_with_code(&[0x00, 0xC3, 0x50, 0x01]);
+        assert_eq!(rom.value.len(), 0x8000);
+        assert_eq!(rom.entry_point, [0x00, 0xC3, 0x50, 0x01]);
+    }
+
+    #[test]
+    fn licensee_codes() {
+        assert!(rom_with_title(b"TETRIS", 0x01).licensed_by_nintendo());
+        assert!(!rom_with_title(b"TETRIS", 0x08).licensed_by_nintendo());
+
+        let mut rom = rom_with_title(b"TETRIS", 0x33);
+        rom.new_licensee_code = *b"01";
+        assert!(rom.licensed_by_nintendo());
+        rom.new_licensee_code = *b"08";
+        assert!(!rom.licensed_by_nintendo());
+    }
+}
@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+pub fn write_png<W: Write>(writer: W, width: u32, height: u32, rgba: &[u8]) -> anyhow::Result<()> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    return Ok(());
+}
+
+pub fn save_png(path: &str, width: u32, height: u32, rgba: &[u8]) -> anyhow::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    return write_png(writer, width, height, rgba);
+}
+
+/// Decodes a PNG into RGBA pixels, returns (width, height, pixels)
+pub fn load_png(path: &str) -> anyhow::Result<(u32, u32, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // expand palettes and low bit depths to 8-bit channels
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    let rgba = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 0xFF])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&p| [p, p, p, 0xFF]).collect(),
+        t => anyhow::bail!("unsupported PNG color type {:?}: {}", t, path),
+    };
+
+    return Ok((info.width, info.height, rgba));
+}
@@ -0,0 +1,569 @@
+use crate::joypad::Joypad;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const BORDER_WIDTH: usize = 256;
+pub const BORDER_HEIGHT: usize = 224;
+// Position of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+// The screen is split into 20x18 cells of 8x8 pixels for the attributes
+const ATTR_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT / 8;
+// 4 KB copied from the screen by the *_TRN commands
+const TRANSFER_SIZE: usize = 0x1000;
+const ATTR_FILE_SIZE: usize = 90;
+const ATTR_FILES: usize = 45;
+
+/// Commands sent to the SGB through P1
+/// <https://gbdev.io/pandocs/SGB_Command_Summary.html>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    // tiles $00-$7F or $80-$FF
+    Chr(bool),
+    Pct,
+    Pal,
+    Attr,
+}
+
+/// Super Game Boy functions
+/// <https://gbdev.io/pandocs/SGB_Functions.html>
+///
+/// Packets are sent bit by bit by pulsing P14 (0) and P15 (1) low, starting
+/// with both low. Data too large for packets is taken from the next frame,
+/// where the game displays it as 2bpp tiles.
+#[derive(Clone)]
+pub struct Sgb {
+    // 4 palettes for the Game Boy screen, color 0 is shared
+    pub palettes: [[u16; 4]; 4],
+    // Palette of each 8x8 cell of the screen
+    pub attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    // MASK_EN: 0 = off, 1 = freeze, 2 = black, 3 = color 0
+    pub mask: u8,
+    // Game Boy screen colored by the palettes
+    pub screen: Vec<u16>,
+    // Border with the screen in the middle
+    pub framebuffer: Vec<u16>,
+
+    system_palettes: Vec<[u16; 4]>,
+    attr_files: Vec<u8>,
+    // 256 SNES 4bpp tiles from CHR_TRN
+    border_tiles: Vec<u8>,
+    // 32x28 tilemap from PCT_TRN
+    border_map: Vec<u16>,
+    // palettes 4-7 from PCT_TRN
+    border_palettes: [[u16; 16]; 4],
+
+    // packet being received
+    packet: [u8; PACKET_SIZE],
+    bit: usize,
+    receiving: bool,
+    // pulses have to be separated by P14 and P15 both high
+    waiting_for_high: bool,
+    // packets of the current command
+    command: Vec<u8>,
+    // with the frame in progress when it was requested
+    pending_transfer: Option<(Transfer, u64)>,
+    // last frame rendered
+    frame: u64,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        // Default colors of the SGB before any PAL command
+        let grey = [0x7FFF, 0x5294, 0x294A, 0x0000];
+        return Sgb {
+            palettes: [grey; 4],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            mask: 0,
+            screen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            framebuffer: vec![0; BORDER_WIDTH * BORDER_HEIGHT],
+
+            system_palettes: vec![[0; 4]; 512],
+            attr_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32],
+            border_palettes: [[0; 16]; 4],
+
+            packet: [0; PACKET_SIZE],
+            bit: 0,
+            receiving: false,
+            waiting_for_high: false,
+            command: Vec::new(),
+            pending_transfer: None,
+            frame: 0,
+        };
+    }
+
+    /// Called on every write to P1
+    pub fn write_p1(&mut self, val: u8, joypad: &mut Joypad) {
+        match val & 0x30 {
+            // Reset pulse, starts a packet
+            0x00 => {
+                self.receiving = true;
+                self.waiting_for_high = true;
+                self.packet = [0; PACKET_SIZE];
+                self.bit = 0;
+            }
+            0x30 => self.waiting_for_high = false,
+            select => {
+                if !self.receiving || self.waiting_for_high {
+                    return;
+                }
+                self.waiting_for_high = true;
+                let one = select == 0x10;
+
+                // 128 data bits followed by a 0 stop bit
+                if self.bit == PACKET_SIZE * 8 {
+                    self.receiving = false;
+                    if !one {
+                        self.receive_packet(joypad);
+                    }
+                    return;
+                }
+                if one {
+                    self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+                }
+                self.bit += 1;
+            }
+        }
+    }
+
+    fn receive_packet(&mut self, joypad: &mut Joypad) {
+        self.command.extend_from_slice(&self.packet);
+        // The first byte holds the command and the number of packets
+        let length = (self.command[0] & 0x07).max(1) as usize;
+        if self.command.len() < length * PACKET_SIZE {
+            return;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        self.execute(&command, joypad);
+    }
+
+    fn execute(&mut self, data: &[u8], joypad: &mut Joypad) {
+        match data[0] >> 3 {
+            0x00 => self.set_palette_pair(data, 0, 1),
+            0x01 => self.set_palette_pair(data, 2, 3),
+            0x02 => self.set_palette_pair(data, 0, 3),
+            0x03 => self.set_palette_pair(data, 1, 2),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => self.request_transfer(Transfer::Pal),
+            // MLT_REQ: 1, 2 or 4 joypads
+            0x11 => {
+                joypad.players = match data[1] & 0x03 {
+                    0 => 1,
+                    1 => 2,
+                    _ => 4,
+                };
+                joypad.current = 0;
+            }
+            0x13 => self.request_transfer(Transfer::Chr(data[1] & 0x01 != 0)),
+            0x14 => self.request_transfer(Transfer::Pct),
+            0x15 => self.request_transfer(Transfer::Attr),
+            0x16 => {
+                self.apply_attr_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = 0;
+                }
+            }
+            0x17 => self.mask = data[1] & 0x03,
+            // Sound, SNES code uploads and the rest have no visible effect
+            _ => (),
+        }
+    }
+
+    /// PAL01, PAL23, PAL03, PAL12: color 0 followed by colors 1-3 of both palettes
+    fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x7FFF;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    /// ATTR_BLK: palettes inside, on the border of and outside rectangles
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(18);
+        for set in data[2..].chunks(6).take(count) {
+            if set.len() < 6 {
+                break;
+            }
+            let (control, palettes) = (set[0] & 0x07, set[1]);
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+            let inside = palettes & 0x03;
+            let outside = (palettes >> 4) & 0x03;
+            // With only one of inside/outside set, the border takes its palette
+            let border = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => (palettes >> 2) & 0x03,
+            };
+            let border_on = control & 0x02 != 0 || control == 0x01 || control == 0x04;
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_border {
+                        border_on.then_some(border)
+                    } else if within {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else {
+                        (control & 0x04 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: palettes of whole rows or columns
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for line in data[2..].iter().take(count) {
+            let index = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                // horizontal line
+                if index < ATTR_HEIGHT {
+                    self.attributes[index * ATTR_WIDTH..(index + 1) * ATTR_WIDTH].fill(palette);
+                }
+            } else if index < ATTR_WIDTH {
+                for y in 0..ATTR_HEIGHT {
+                    self.attributes[y * ATTR_WIDTH + index] = palette;
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV: splits the screen in two at a row or column
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTR_WIDTH + x] = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    /// ATTR_CHR: palettes of consecutive cells, 4 per byte
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(ATTR_WIDTH * ATTR_HEIGHT);
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count {
+            let Some(byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            if x >= ATTR_WIDTH || y >= ATTR_HEIGHT {
+                break;
+            }
+            self.attributes[y * ATTR_WIDTH + x] = (byte >> (6 - (i % 4) * 2)) & 0x03;
+
+            if vertical {
+                y += 1;
+                if y == ATTR_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// PAL_SET: copies 4 of the system palettes sent by PAL_TRN
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let index = u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) as usize & 0x1FF;
+            self.palettes[i] = self.system_palettes[index];
+        }
+        // Color 0 of the first palette is used by all of them
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+
+        if data[9] & 0x80 != 0 {
+            self.apply_attr_file(data[9] & 0x3F);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    fn apply_attr_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTR_FILES {
+            return;
+        }
+        let bytes = &self.attr_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (bytes[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+        }
+    }
+
+    fn request_transfer(&mut self, transfer: Transfer) -> () {
+        self.pending_transfer = Some((transfer, self.frame));
+    }
+
+    /// Called after every bus tick, renders once per completed PPU frame
+    pub fn update(&mut self, frame: u64, framebuffer: &[u8]) {
+        if frame != self.frame {
+            self.frame = frame;
+            self.end_frame(framebuffer);
+        }
+    }
+
+    fn end_frame(&mut self, framebuffer: &[u8]) {
+        // The frame in progress when the command was sent is partly drawn
+        // before it, the data is taken from the next complete one
+        if let Some((transfer, requested)) = self.pending_transfer {
+            if self.frame >= requested + 2 {
+                self.pending_transfer = None;
+                self.transfer(transfer, &vram_transfer_data(framebuffer));
+            }
+        }
+
+        match self.mask {
+            1 => (),
+            2 => self.screen.fill(0),
+            3 => self.screen.fill(self.palettes[0][0]),
+            _ => {
+                for (i, (pixel, shade)) in self.screen.iter_mut().zip(framebuffer).enumerate() {
+                    let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                    let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8];
+                    *pixel = self.palettes[palette as usize][*shade as usize & 0x03];
+                }
+            }
+        }
+        self.render_border();
+    }
+
+    fn transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Chr(high) => {
+                let start = if high { TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::Pct => {
+                for (entry, bytes) in self.border_map.iter_mut().zip(data[..0x800].chunks(2)) {
+                    *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                for (i, bytes) in data[0x800..0x880].chunks(2).enumerate() {
+                    self.border_palettes[i / 16][i % 16] =
+                        u16::from_le_bytes([bytes[0], bytes[1]]) & 0x7FFF;
+                }
+            }
+            Transfer::Pal => {
+                for (palette, bytes) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    for (i, color) in palette.iter_mut().enumerate() {
+                        *color = u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]) & 0x7FFF;
+                    }
+                }
+            }
+            Transfer::Attr => {
+                self.attr_files
+                    .copy_from_slice(&data[..ATTR_FILES * ATTR_FILE_SIZE]);
+            }
+        }
+    }
+
+    /// Draws the border over the colored screen, color 0 of the border is transparent
+    fn render_border(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..BORDER_HEIGHT {
+            for x in 0..BORDER_WIDTH {
+                let entry = self.border_map[(y / 8) * 32 + x / 8];
+                let tile = (entry & 0xFF) as usize;
+                let palette = ((entry >> 10) & 0x03) as usize;
+                let tx = if entry & 0x4000 != 0 {
+                    7 - x % 8
+                } else {
+                    x % 8
+                };
+                let ty = if entry & 0x8000 != 0 {
+                    7 - y % 8
+                } else {
+                    y % 8
+                };
+                let color = snes_tile_pixel(&self.border_tiles[tile * 32..tile * 32 + 32], tx, ty);
+
+                let in_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                self.framebuffer[y * BORDER_WIDTH + x] = if color != 0 {
+                    self.border_palettes[palette][color as usize]
+                } else if in_screen {
+                    self.screen[(y - SCREEN_Y) * SCREEN_WIDTH + x - SCREEN_X]
+                } else {
+                    backdrop
+                };
+            }
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Sgb {
+        return Sgb::new();
+    }
+}
+
+/// Re-encodes the first 256 tiles of the screen (20 per row) as 2bpp tile data
+fn vram_transfer_data(framebuffer: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(TRANSFER_SIZE);
+    for tile in 0..TRANSFER_SIZE / 16 {
+        let (tx, ty) = (tile % ATTR_WIDTH * 8, tile / ATTR_WIDTH * 8);
+        for row in 0..8 {
+            let (mut low, mut high) = (0u8, 0u8);
+            for col in 0..8 {
+                let shade = framebuffer[(ty + row) * SCREEN_WIDTH + tx + col];
+                low |= (shade & 0x01) << (7 - col);
+                high |= ((shade >> 1) & 0x01) << (7 - col);
+            }
+            data.push(low);
+            data.push(high);
+        }
+    }
+    return data;
+}
+
+/// SNES 4bpp tiles store bitplanes 0/1 in the first 16 bytes and 2/3 in the last 16
+fn snes_tile_pixel(tile: &[u8], x: usize, y: usize) -> u8 {
+    let bit = 7 - x;
+    let mut color = 0;
+    for (plane, offset) in [y * 2, y * 2 + 1, 16 + y * 2, 16 + y * 2 + 1]
+        .iter()
+        .enumerate()
+    {
+        color |= ((tile[*offset] >> bit) & 0x01) << plane;
+    }
+    return color;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // command << 3 | number of packets
+    const PAL01: u8 = 0x01;
+    const ATTR_BLK: u8 = 0x04 << 3 | 1;
+    const ATTR_LIN: u8 = 0x05 << 3 | 2;
+    const MLT_REQ: u8 = 0x11 << 3 | 1;
+
+    /// Pulses `data` through P1 as packets: a reset, 128 data bits and the stop bit
+    fn send(sgb: &mut Sgb, joypad: &mut Joypad, data: &[u8], stop: u8) -> () {
+        for packet in data.chunks(PACKET_SIZE) {
+            sgb.write_p1(0x00, joypad);
+            sgb.write_p1(0x30, joypad);
+            for bit in 0..PACKET_SIZE * 8 {
+                let one = packet
+                    .get(bit / 8)
+                    .is_some_and(|byte| (byte >> (bit % 8)) & 1 != 0);
+                sgb.write_p1(if one { 0x10 } else { 0x20 }, joypad);
+                sgb.write_p1(0x30, joypad);
+            }
+            sgb.write_p1(stop, joypad);
+            sgb.write_p1(0x30, joypad);
+        }
+    }
+
+    #[test]
+    fn pal01_sets_palettes_and_shared_color() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        let colors: [u16; 7] = [0x1234, 0x0001, 0x0002, 0x0003, 0x7C00, 0x03E0, 0x001F];
+        let mut packet = vec![PAL01];
+        packet.extend(colors.iter().flat_map(|color| color.to_le_bytes()));
+        send(&mut sgb, &mut joypad, &packet, 0x20);
+
+        assert_eq!(sgb.palettes[0], [0x1234, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[1], [0x1234, 0x7C00, 0x03E0, 0x001F]);
+        assert_eq!(sgb.palettes[2], [0x1234, 0x5294, 0x294A, 0x0000]);
+    }
+
+    #[test]
+    fn packet_without_stop_bit_is_dropped() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        send(&mut sgb, &mut joypad, &[MLT_REQ, 0x01], 0x10);
+        assert_eq!(joypad.players, 1);
+
+        send(&mut sgb, &mut joypad, &[MLT_REQ, 0x01], 0x20);
+        assert_eq!(joypad.players, 2);
+    }
+
+    #[test]
+    fn mlt_req_selects_players() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        for (request, players) in [(0x01, 2), (0x03, 4), (0x02, 4), (0x00, 1)] {
+            send(&mut sgb, &mut joypad, &[MLT_REQ, request], 0x20);
+            assert_eq!(joypad.players, players);
+            assert_eq!(joypad.current, 0);
+        }
+    }
+
+    #[test]
+    fn attr_blk_colors_inside_and_border() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        // one set, inside only, palette 2, cells (1, 2)-(3, 4)
+        send(
+            &mut sgb,
+            &mut joypad,
+            &[ATTR_BLK, 1, 0x01, 0x02, 1, 2, 3, 4],
+            0x20,
+        );
+
+        let attribute = |x: usize, y: usize| sgb.attributes[y * ATTR_WIDTH + x];
+        assert_eq!(attribute(1, 2), 2);
+        assert_eq!(attribute(2, 3), 2);
+        assert_eq!(attribute(3, 4), 2);
+        assert_eq!(attribute(0, 2), 0);
+        assert_eq!(attribute(3, 5), 0);
+    }
+
+    #[test]
+    fn command_waits_for_all_its_packets() {
+        let (mut sgb, mut joypad) = (Sgb::new(), Joypad::new());
+        // two packets, horizontal line 5 with palette 3
+        let mut data = [0; PACKET_SIZE * 2];
+        data[..3].copy_from_slice(&[ATTR_LIN, 1, 0x80 | 3 << 5 | 5]);
+
+        send(&mut sgb, &mut joypad, &data[..PACKET_SIZE], 0x20);
+        assert!(sgb.attributes.iter().all(|&palette| palette == 0));
+
+        send(&mut sgb, &mut joypad, &data[PACKET_SIZE..], 0x20);
+        let row = &sgb.attributes[5 * ATTR_WIDTH..6 * ATTR_WIDTH];
+        assert!(row.iter().all(|&palette| palette == 3));
+        assert_eq!(sgb.attributes[4 * ATTR_WIDTH], 0);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::bus::Bus;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Labels from an RGBDS or no$gmb `.sym` file, one `BANK:ADDR Label` per line
+/// <https://rgbds.gbdev.io/docs/rgblink.1#FILES>
+///
+/// `;` starts a comment. Banks only tell labels apart in the switchable
+/// $4000-$7FFF and $A000-$BFFF windows, where the MBC picks which one is mapped.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    // several banks can have a label at the same address
+    by_addr: BTreeMap<u16, Vec<(usize, String)>>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+/// A label and how far an address is past it
+pub struct Location<'a> {
+    pub label: &'a str,
+    pub offset: u16,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.offset == 0 {
+            return write!(f, "{}", self.label);
+        }
+        return write!(f, "{}+${:X}", self.label, self.offset);
+    }
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> anyhow::Result<Symbols> {
+        return Symbols::parse(&std::fs::read_to_string(path)?);
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Symbols> {
+        let mut symbols = Symbols::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(location, label)| {
+                    let (bank, addr) = location.split_once(':')?;
+                    let bank = usize::from_str_radix(bank, 16).ok()?;
+                    let addr = u16::from_str_radix(addr, 16).ok()?;
+                    return Some((bank, addr, label.trim()));
+                });
+            match parsed {
+                Some((bank, addr, label)) => symbols.insert(bank, addr, label),
+                None => anyhow::bail!("invalid symbol on line {}: {}", i + 1, line),
+            }
+        }
+        return Ok(symbols);
+    }
+
+    pub fn insert(&mut self, bank: usize, addr: u16, label: &str) -> () {
+        self.by_addr
+            .entry(addr)
+            .or_default()
+            .push((bank, label.to_string()));
+        self.by_name.insert(label.to_string(), (bank, addr));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.by_name.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.by_name.is_empty();
+    }
+
+    /// Bank and address of a label
+    pub fn address_of(&self, label: &str) -> Option<(usize, u16)> {
+        return self.by_name.get(label).copied();
+    }
+
+    /// Label at exactly `addr`, in the bank currently mapped there
+    pub fn label_at(&self, bus: &Bus, addr: u16) -> Option<&str> {
+        return self
+            .locate(bus, addr)
+            .filter(|location| location.offset == 0)
+            .map(|location| location.label);
+    }
+
+    /// The closest label at or before `addr`, in the bank currently mapped there
+    pub fn locate(&self, bus: &Bus, addr: u16) -> Option<Location<'_>> {
+        return self.locate_in(bus.bank(addr), addr);
+    }
+
+    /// The closest label at or before `addr` in the same memory region.
+    /// Without a bank, labels of any bank match
+    pub fn locate_in(&self, bank: Option<usize>, addr: u16) -> Option<Location<'_>> {
+        let addr_region = region(addr)?;
+        for (label_addr, labels) in self.by_addr.range(..=addr).rev() {
+            if region(*label_addr) != Some(addr_region) {
+                return None;
+            }
+            let label = labels
+                .iter()
+                .find(|(label_bank, _)| bank.is_none() || bank == Some(*label_bank));
+            if let Some((_, label)) = label {
+                return Some(Location {
+                    label,
+                    offset: addr - label_addr,
+                });
+            }
+        }
+        return None;
+    }
+}
+
+// Labels never extend from one region into the next
+fn region(addr: u16) -> Option<u16> {
+    return match addr {
+        0x0000..=0x3FFF => Some(0x0000),
+        0x4000..=0x7FFF => Some(0x4000),
+        0x8000..=0x9FFF => Some(0x8000),
+        0xA000..=0xBFFF => Some(0xA000),
+        0xC000..=0xCFFF => Some(0xC000),
+        0xD000..=0xDFFF => Some(0xD000),
+        0xFF80..=0xFFFE => Some(0xFF80),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink
+
+00:0100 EntryPoint
+00:0150 Main ; the main loop
+00:3FF0 LastInBank0
+01:4000 BankOneStart
+02:4000 BankTwoStart
+02:4020 BankTwo.loop
+00:C000 wBuffer
+";
+
+    /// The location as shown by the debugger, empty without a label
+    fn locate(symbols: &Symbols, bank: Option<usize>, addr: u16) -> String {
+        return symbols
+            .locate_in(bank, addr)
+            .map_or(String::new(), |location| location.to_string());
+    }
+
+    #[test]
+    fn parses_labels_and_skips_comments() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 7);
+        assert_eq!(symbols.address_of("Main"), Some((0, 0x0150)));
+        assert_eq!(symbols.address_of("BankTwo.loop"), Some((2, 0x4020)));
+        assert_eq!(symbols.address_of("the"), None);
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        for (text, line) in [
+            ("00:0100 Start\n0100 NoBank", 2),
+            ("\nZZ:0100 BadBank", 2),
+            ("00:10000 TooFar", 1),
+            ("00:0100", 1),
+        ] {
+            let error = Symbols::parse(text).err().unwrap().to_string();
+            assert!(
+                error.starts_with(&format!("invalid symbol on line {}:", line)),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn banks_share_an_address() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(locate(&symbols, Some(1), 0x4000), "BankOneStart");
+        assert_eq!(locate(&symbols, Some(2), 0x4000), "BankTwoStart");
+        // the bank 2 label in between is skipped
+        assert_eq!(locate(&symbols, Some(1), 0x4030), "BankOneStart+$30");
+        assert_eq!(locate(&symbols, Some(2), 0x4030), "BankTwo.loop+$10");
+        assert_eq!(locate(&symbols, Some(3), 0x4030), "");
+        // without a bank, any of them
+        assert!(!locate(&symbols, None, 0x4000).is_empty());
+    }
+
+    #[test]
+    fn labels_stop_at_region_boundaries() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(locate(&symbols, Some(0), 0x3FFF), "LastInBank0+$F");
+        assert_eq!(locate(&symbols, Some(3), 0x4000), "");
+        assert_eq!(locate(&symbols, None, 0x00FF), "");
+        assert_eq!(locate(&symbols, None, 0xC0FF), "wBuffer+$FF");
+        assert_eq!(locate(&symbols, None, 0xD000), "");
+        assert_eq!(locate(&symbols, None, 0xE000), "");
+    }
+}
@@ -0,0 +1,100 @@
+use crate::interrupt;
+
+/// DIV, TIMA, TMA and TAC
+/// <https://gbdev.io/pandocs/Timer_and_Divider_Registers.html>
+///
+/// DIV is the upper byte of a 16-bit counter incremented every T-cycle.
+/// TIMA increments on the falling edge of the counter bit selected by TAC.
+#[derive(Clone)]
+pub struct Timer {
+    pub counter: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    // Interrupts requested since the bus last collected them
+    pub interrupts: u8,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        return Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            interrupts: 0,
+        };
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => panic!("Timer::read: invalid address: 0x{:04X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // Any write resets the whole counter, which can cause a falling edge
+            0xFF04 => self.set_counter(0),
+            0xFF05 => self.tima = val,
+            0xFF06 => self.tma = val,
+            0xFF07 => {
+                let before = self.timer_bit();
+                self.tac = val & 0x07;
+                if before && !self.timer_bit() {
+                    self.increment_tima();
+                }
+            }
+            _ => panic!("Timer::write: invalid address: 0x{:04X}", addr),
+        }
+    }
+
+    /// Advances by one M-cycle of the CPU clock
+    pub fn tick(&mut self) {
+        self.set_counter(self.counter.wrapping_add(4));
+    }
+
+    pub fn reset_div(&mut self) {
+        self.set_counter(0);
+    }
+
+    fn set_counter(&mut self, counter: u16) {
+        let before = self.timer_bit();
+        self.counter = counter;
+        if before && !self.timer_bit() {
+            self.increment_tima();
+        }
+    }
+
+    /// Counter bit watched by TIMA, gated by the TAC enable bit
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9, // 4096 Hz
+            1 => 3, // 262144 Hz
+            2 => 5, // 65536 Hz
+            3 => 7, // 16384 Hz
+            _ => unreachable!(),
+        };
+        return self.tac & 0x04 != 0 && (self.counter >> bit) & 1 != 0;
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            self.tima = self.tma;
+            self.interrupts |= interrupt::TIMER;
+        } else {
+            self.tima = tima;
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        return Timer::new();
+    }
+}
@@ -0,0 +1,83 @@
+use crate::cpu::Cpu;
+use crate::symbols::Symbols;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Instruction trace in the Gameboy Doctor format
+/// <https://github.com/robert-w-gries/gameboy-doctor>
+///
+/// One line with the registers and the 4 bytes at PC is written before each instruction.
+/// With symbols, instructions at a label end with a `; Label` comment.
+pub struct Trace {
+    sink: Box<dyn Write>,
+    // first write error, reported by finish()
+    error: Option<std::io::Error>,
+    pub symbols: Option<Symbols>,
+}
+
+impl Trace {
+    pub fn new(sink: Box<dyn Write>) -> Trace {
+        return Trace {
+            sink,
+            error: None,
+            symbols: None,
+        };
+    }
+
+    /// Writes to a file, or to stdout for "-"
+    pub fn from_path(path: &str) -> anyhow::Result<Trace> {
+        if path == "-" {
+            return Ok(Trace::new(Box::new(std::io::stdout())));
+        }
+        return Ok(Trace::new(Box::new(BufWriter::new(File::create(path)?))));
+    }
+
+    pub fn log(&mut self, cpu: &Cpu) {
+        if self.error.is_some() {
+            return;
+        }
+        let label = self
+            .symbols
+            .as_ref()
+            .and_then(|symbols| symbols.label_at(&cpu.bus, cpu.registers.pc));
+        let result = match label {
+            Some(label) => writeln!(self.sink, "{} ; {}", trace_line(cpu), label),
+            None => writeln!(self.sink, "{}", trace_line(cpu)),
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        self.sink.flush()?;
+        return Ok(());
+    }
+}
+
+/// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+pub fn trace_line(cpu: &Cpu) -> String {
+    let r = &cpu.registers;
+    let pc = r.pc;
+    let pcmem: Vec<String> = (0..4)
+        .map(|i| format!("{:02X}", cpu.bus.peek(pc.wrapping_add(i))))
+        .collect();
+    return format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        r.a,
+        cpu.flag_registers.bits(),
+        r.bc >> 8,
+        r.bc & 0xFF,
+        r.de >> 8,
+        r.de & 0xFF,
+        r.hl >> 8,
+        r.hl & 0xFF,
+        r.sp,
+        pc,
+        pcmem.join(",")
+    );
+}
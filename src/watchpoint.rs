@@ -0,0 +1,103 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // a write that changes the stored value
+    Change,
+}
+
+impl WatchKind {
+    pub fn parse(name: &str) -> Option<WatchKind> {
+        return match name {
+            "r" | "read" => Some(WatchKind::Read),
+            "w" | "write" => Some(WatchKind::Write),
+            "c" | "change" => Some(WatchKind::Change),
+            _ => None,
+        };
+    }
+}
+
+/// Pauses execution on CPU accesses to `start..=end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    // Only matches while this bank is mapped, for $4000-$7FFF and $A000-$BFFF
+    pub bank: Option<usize>,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, kind: WatchKind) -> Watchpoint {
+        return Watchpoint {
+            start,
+            end,
+            kind,
+            bank: None,
+        };
+    }
+
+    pub fn with_bank(self, bank: usize) -> Watchpoint {
+        return Watchpoint {
+            bank: Some(bank),
+            ..self
+        };
+    }
+
+    pub fn contains(&self, addr: u16, bank: Option<usize>) -> bool {
+        if addr < self.start || addr > self.end {
+            return false;
+        }
+        return self.bank.is_none() || self.bank == bank;
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} $", self.kind)?;
+        if let Some(bank) = self.bank {
+            write!(f, "{:02X}:", bank)?;
+        }
+        write!(f, "{:04X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-${:04X}", self.end)?;
+        }
+        return Ok(());
+    }
+}
+
+/// An access that matched a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    // start of the instruction making the access
+    pub pc: u16,
+    pub addr: u16,
+    pub bank: Option<usize>,
+    // equal for reads
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = match self.bank {
+            Some(bank) => format!("{:02X}:{:04X}", bank, self.addr),
+            None => format!("{:04X}", self.addr),
+        };
+        return match self.watchpoint.kind {
+            WatchKind::Read => write!(
+                f,
+                "Read ${} = ${:02X} at PC=${:04X}",
+                addr, self.old, self.pc
+            ),
+            _ => write!(
+                f,
+                "Write ${}: ${:02X} -> ${:02X} at PC=${:04X}",
+                addr, self.old, self.new, self.pc
+            ),
+        };
+    }
+}
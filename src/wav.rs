@@ -0,0 +1,83 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// 16-bit PCM WAV encoder for stereo samples
+/// <http://soundfile.sapp.org/doc/WaveFormat/>
+pub fn write_wav<W: Write>(
+    writer: &mut W,
+    sample_rate: u32,
+    samples: &[(f32, f32)],
+) -> std::io::Result<()> {
+    write_header(writer, sample_rate, 2, samples.len() as u32)?;
+    for (left, right) in samples {
+        writer.write_all(&to_i16(*left).to_le_bytes())?;
+        writer.write_all(&to_i16(*right).to_le_bytes())?;
+    }
+
+    return Ok(());
+}
+
+/// 16-bit PCM WAV encoder for a single channel
+pub fn write_wav_mono<W: Write>(
+    writer: &mut W,
+    sample_rate: u32,
+    samples: &[f32],
+) -> std::io::Result<()> {
+    write_header(writer, sample_rate, 1, samples.len() as u32)?;
+    for val in samples {
+        writer.write_all(&to_i16(*val).to_le_bytes())?;
+    }
+
+    return Ok(());
+}
+
+pub fn save_wav(path: &str, sample_rate: u32, samples: &[(f32, f32)]) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_wav(&mut writer, sample_rate, samples)?;
+    writer.flush()?;
+    return Ok(());
+}
+
+pub fn save_wav_mono(path: &str, sample_rate: u32, samples: &[f32]) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_wav_mono(&mut writer, sample_rate, samples)?;
+    writer.flush()?;
+    return Ok(());
+}
+
+fn write_header<W: Write>(
+    writer: &mut W,
+    sample_rate: u32,
+    channels: u16,
+    frames: u32,
+) -> std::io::Result<()> {
+    let bits_per_sample: u16 = 16;
+    let block_align = channels * bits_per_sample / 8;
+    let byte_rate = sample_rate * block_align as u32;
+    let data_len = frames * block_align as u32;
+
+    // RIFF header
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    // fmt chunk
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // PCM
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&bits_per_sample.to_le_bytes())?;
+
+    // data chunk
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+
+    return Ok(());
+}
+
+fn to_i16(val: f32) -> i16 {
+    return (val.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+}
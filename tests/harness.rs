@@ -0,0 +1,41 @@
+use gb::harness::compare;
+
+const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+const BLACK: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+
+fn image(pixels: &[[u8; 4]]) -> Vec<u8> {
+    pixels.concat()
+}
+
+#[test]
+fn identical_images_match() {
+    let expected = image(&[WHITE, BLACK, BLACK, WHITE]);
+    let diff = compare(&expected, &expected, 2, 2);
+    assert!(diff.is_match());
+    assert_eq!(diff.image.len(), expected.len());
+    assert!(diff.report().starts_with("0 of 4 pixels differ (0.00%)"));
+}
+
+#[test]
+fn alpha_is_ignored() {
+    let expected = image(&[WHITE, BLACK]);
+    let actual = image(&[[0xFF, 0xFF, 0xFF, 0x00], BLACK]);
+    assert!(compare(&expected, &actual, 2, 1).is_match());
+}
+
+#[test]
+fn mismatches_are_located_and_reported() {
+    let expected = image(&[WHITE, WHITE, WHITE, WHITE, WHITE, WHITE]);
+    let actual = image(&[WHITE, BLACK, WHITE, WHITE, WHITE, BLACK]);
+    let diff = compare(&expected, &actual, 3, 2);
+
+    assert!(!diff.is_match());
+    let positions: Vec<(usize, usize)> = diff.mismatches.iter().map(|m| (m.x, m.y)).collect();
+    assert_eq!(positions, vec![(1, 0), (2, 1)]);
+    assert_eq!(&diff.image[4..8], &[0xFF, 0x00, 0x00, 0xFF]);
+
+    let report = diff.report();
+    assert!(report.contains("2 of 6 pixels differ (33.33%)"));
+    assert!(report.contains("differences within (1, 0)-(2, 1)"));
+    assert!(report.contains("(  1,   0) expected #FFFFFF actual #000000"));
+}
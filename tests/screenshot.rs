@@ -0,0 +1,25 @@
+use gb::harness::ScreenshotTest;
+
+// Failing runs leave <rom>.actual.png and <rom>.diff.png here
+const OUTPUT_DIR: &str = "target/screenshot_tests";
+
+/// Test ROMs are not distributed with the repository, tests that need one are
+/// ignored and run with `cargo test -- --ignored` once it is checked out
+fn run(rom_path: &str, reference_path: &str, frames: u64) {
+    let test = ScreenshotTest {
+        rom_path,
+        reference_path,
+        frames,
+        output_dir: OUTPUT_DIR,
+    };
+    if let Err(e) = test.run() {
+        panic!("{}", e);
+    }
+}
+
+// https://github.com/mattcurrie/dmg-acid2
+#[test]
+#[ignore = "needs test_roms/dmg-acid2.gb"]
+fn dmg_acid2() {
+    run("test_roms/dmg-acid2.gb", "test_roms/dmg-acid2.png", 60);
+}